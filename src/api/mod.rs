@@ -1,27 +1,29 @@
 use ::reqwest::IntoUrl;
-use governor::{Quota, RateLimiter};
-use http::header::CONTENT_DISPOSITION;
+use http::header::{CONTENT_DISPOSITION, RETRY_AFTER};
 use http::Method;
 use indicatif::ProgressStyle;
-use nonzero_ext::*;
-use pollster::FutureExt as _;
 use reqwest::blocking as reqwest;
 use serde::Serialize;
 use soup::prelude::*;
 use std::error::Error;
 use std::fs::{self, File};
-use std::io::BufReader;
-use std::path::Path;
-use std::str;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 pub mod structs;
 use crate::api::structs::*;
 use crate::cookies;
+use crate::layout::Layout;
+use crate::sanitise::truncate_name;
+use crate::throttle::{RequestClass, Throttle};
 use crate::util;
 
 pub struct BandcampPage {
     pub download_urls: DownloadsMap,
+    /// Collection items, keyed by the same ID as `download_urls`.
+    pub items: ItemsMap,
     // pub page_name: String,
 }
 
@@ -34,23 +36,58 @@ struct PostCollectionBody<'a> {
 
 const MAX_RETRIES: u8 = 5;
 
+/// How long to wait after being throttled if Bandcamp doesn't say otherwise.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Settings for how the `Api` talks to Bandcamp.
+pub struct ApiOptions {
+    pub transfer_limits: util::TransferLimits,
+    /// Maximum amount of downloads to run at once.
+    pub max_jobs: usize,
+    /// Maximum amount of page requests to send per second.
+    pub max_rate: f64,
+    /// Maximum combined download speed in KiB/s, if any.
+    pub max_download_speed: Option<NonZeroU32>,
+}
+
+impl Default for ApiOptions {
+    fn default() -> Self {
+        Self {
+            transfer_limits: util::TransferLimits::default(),
+            max_jobs: 4,
+            max_rate: 3.0,
+            max_download_speed: None,
+        }
+    }
+}
+
 pub struct Api {
     pub client: reqwest::Client,
-    ratelimiter: governor::DefaultDirectRateLimiter,
+    pub throttle: Throttle,
+    transfer_limits: util::TransferLimits,
+    bandwidth: Option<governor::DefaultDirectRateLimiter>,
 }
 
 impl Api {
     pub fn new(cookies: Vec<cookies::RawCookie>) -> Self {
+        Self::with_options(cookies, ApiOptions::default())
+    }
+
+    pub fn with_options(cookies: Vec<cookies::RawCookie>, options: ApiOptions) -> Self {
         let cookie_jar = cookies::fill_cookie_jar(cookies);
+        // Blocking reads of a response body time out individually, so this
+        // also catches streams that stop sending bytes entirely.
         let client = reqwest::ClientBuilder::new()
             .cookie_provider(Arc::new(cookie_jar))
+            .timeout(options.transfer_limits.stall_timeout)
             .build()
             .unwrap();
-        let ratelimiter = RateLimiter::direct(Quota::per_second(nonzero!(3u32)));
 
         Self {
             client,
-            ratelimiter,
+            throttle: Throttle::new(options.max_jobs, options.max_rate),
+            transfer_limits: options.transfer_limits,
+            bandwidth: options.max_download_speed.map(util::bandwidth_limiter),
         }
     }
 
@@ -60,21 +97,23 @@ impl Api {
 
     fn request<U: IntoUrl + Copy>(
         &self,
+        class: RequestClass,
         method: Method,
         url: U,
     ) -> Result<reqwest::Response, Box<dyn Error>> {
-        self.request_with_retry(method, url, 0)
+        self.request_with_retry(class, method, url, 0)
     }
 
     fn request_with_retry<U: IntoUrl + Copy>(
         &self,
+        class: RequestClass,
         method: Method,
         url: U,
         retry_attempt: u8,
     ) -> Result<reqwest::Response, Box<dyn Error>> {
-        self.ratelimiter.until_ready().block_on();
+        self.throttle.until_ready(class);
 
-        let response = self.client.request(method.clone(), url.clone()).send()?;
+        let response = self.client.request(method.clone(), url).send()?;
         let status: http::StatusCode = response.status();
 
         if !status.is_success() {
@@ -89,11 +128,25 @@ impl Api {
                 bail!(format!("reached maximum retries for url {}", url.as_str()));
             }
 
-            warn!("hit ratelimit from Bandcamp, sleeping for 10 seconds");
-            std::thread::sleep(std::time::Duration::from_secs(10));
-            return self.request_with_retry(method, url, retry_attempt + 1);
+            self.throttle.on_throttled(class);
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_RETRY_AFTER)
+                .max(DEFAULT_RETRY_AFTER);
+
+            warn!(
+                "hit ratelimit from Bandcamp, sleeping for {} seconds",
+                retry_after.as_secs()
+            );
+            std::thread::sleep(retry_after);
+            return self.request_with_retry(class, method, url, retry_attempt + 1);
         }
 
+        self.throttle.on_success(class);
         Ok(response)
     }
 
@@ -110,7 +163,7 @@ impl Api {
             .filter_map(|(id, url)| {
                 items
                     .iter()
-                    .find(|v| &format!("{}{}", v.sale_item_type, v.sale_item_id) == id)
+                    .find(|v| &v.download_id() == id)
                     .filter(|item| artist.is_none_or(|v| item.band_name.eq_ignore_ascii_case(v)))
                     .filter(|item| album.is_none_or(|v| item.item_title.eq_ignore_ascii_case(v)))
                     .map(|_| (id.clone(), url.clone()))
@@ -121,7 +174,9 @@ impl Api {
     fn download_fanpage_data(&self, name: &str) -> Result<ParsedFanpageData, Box<dyn Error>> {
         debug!("`download_fanpage_data` for Bandcamp page '{name}'");
 
-        let body = self.request(Method::GET, &Self::bc_path(name))?.text()?;
+        let body = self
+            .request(RequestClass::Page, Method::GET, &Self::bc_path(name))?
+            .text()?;
         let soup = Soup::new(&body);
 
         let data_el = soup
@@ -147,18 +202,17 @@ impl Api {
     ) -> Result<BandcampPage, Box<dyn Error>> {
         debug!("`get_download_urls` for Bandcamp page '{name}'");
 
-        let fanpage_data = self.download_fanpage_data(&name)?;
+        let fanpage_data = self.download_fanpage_data(name)?;
         let items = fanpage_data
             .item_cache
             .collection
             .values()
             .collect::<Vec<&Item>>();
 
-        match fanpage_data.fan_data.is_own_page {
-            Some(true) => (),
-            _ => bail!(format!(
+        if fanpage_data.fan_data.is_own_page != Some(true) {
+            bail!(format!(
                 r#"Failed to scrape collection data for "{name}" (`is_own_page` is false). Perhaps check your cookies, or your spelling."#
-            )),
+            ));
         }
 
         let mut collection = Self::filter_download_map(
@@ -167,6 +221,10 @@ impl Api {
             album,
             artist,
         );
+        let mut collection_items = items
+            .iter()
+            .map(|item| (item.download_id(), (*item).clone()))
+            .collect::<ItemsMap>();
 
         let skip_hidden_items = true;
         if skip_hidden_items {
@@ -181,13 +239,14 @@ impl Api {
                 // This should never be `None` thanks to the comparison above.
                 fanpage_data.collection_data.item_count.unwrap()
             );
-            let rest = self.get_rest_downloads_in_collection(
+            let (rest, rest_items) = self.get_rest_downloads_in_collection(
                 &fanpage_data,
                 "collection_items",
                 album,
                 artist,
             )?;
             collection.extend(rest);
+            collection_items.extend(rest_items);
         }
 
         if !skip_hidden_items
@@ -197,19 +256,21 @@ impl Api {
                 "Too many in `hidden_data`, and we're told not to skip, so we need to paginate ({} total)",
                 fanpage_data.hidden_data.item_count.unwrap()
             );
-            let rest = self.get_rest_downloads_in_collection(
+            let (rest, rest_items) = self.get_rest_downloads_in_collection(
                 &fanpage_data,
                 "hidden_items",
                 album,
                 artist,
             )?;
             collection.extend(rest);
+            collection_items.extend(rest_items);
         }
 
         debug!("Successfully retrieved all download URLs");
         Ok(BandcampPage {
             // page_name: title,
             download_urls: collection,
+            items: collection_items,
         })
     }
 
@@ -220,17 +281,20 @@ impl Api {
         collection_name: &str,
         album: Option<&String>,
         artist: Option<&String>,
-    ) -> Result<DownloadsMap, Box<dyn Error>> {
+    ) -> Result<(DownloadsMap, ItemsMap), Box<dyn Error>> {
         debug!("Paginating results for {collection_name}");
         let collection_data = match collection_name {
             "collection_items" => &data.collection_data,
             "hidden_items" => &data.hidden_data,
-            x => bail!(format!(r#"unexpected value for `collection_name`: "{x}""#)),
+            x => {
+                bail!(format!(r#"unexpected value for `collection_name`: "{x}""#));
+            }
         };
 
         let mut last_token = collection_data.last_token.clone().unwrap();
         let mut more_available = true;
         let mut collection = DownloadsMap::new();
+        let mut collection_items = ItemsMap::new();
 
         while more_available {
             trace!("More items to collect, looping...");
@@ -241,7 +305,7 @@ impl Api {
             };
             let body = self
                 .client
-                .post(&Self::bc_path(&format!(
+                .post(Self::bc_path(&format!(
                     "api/fancollection/1/{collection_name}"
                 )))
                 .json(&body)
@@ -254,12 +318,17 @@ impl Api {
             trace!("Collected {} items", redownload_urls.len());
 
             collection.extend(redownload_urls);
+            collection_items.extend(
+                body.items
+                    .into_iter()
+                    .map(|item| (item.download_id(), item)),
+            );
             more_available = body.more_available;
             last_token = body.last_token;
         }
 
         debug!("Finished paginating results for {collection_name}");
-        Ok(collection)
+        Ok((collection, collection_items))
     }
 
     pub fn get_digital_item(
@@ -268,19 +337,19 @@ impl Api {
         debug: &bool,
     ) -> Result<Option<DigitalItem>, Box<dyn Error>> {
         debug!("Retrieving digital item information for {url}");
-        let text = self.request(Method::GET, url)?.text()?;
+        let text = self.request(RequestClass::Page, Method::GET, url)?.text()?;
         let soup = Soup::new(&text);
 
         let download_page_blob = soup
             .attr("id", "pagedata")
             .find()
-            .expect(&format!(
-                "could not find `pagedata` element for digital item {url}"
-            ))
+            .unwrap_or_else(|| panic!("could not find `pagedata` element for digital item {url}"))
             .get("data-blob")
-            .expect(&format!(
-                "could not extract `data-blob` from the pagedata element for digital item {url}"
-            ));
+            .unwrap_or_else(|| {
+                panic!(
+                    "could not extract `data-blob` from the pagedata element for digital item {url}"
+                )
+            });
 
         let item_result = std::panic::catch_unwind(|| {
             serde_json::from_str::<ParsedItemsData>(&download_page_blob).unwrap()
@@ -294,7 +363,7 @@ impl Api {
                 println!("Run with `--debug` to see the full JSON blob.\n")
             }
 
-            bail!(format!("failed parsing {url}"))
+            bail!(format!("failed parsing {url}"));
         }
 
         let item = item_result.unwrap().digital_items.first().cloned();
@@ -302,13 +371,17 @@ impl Api {
         Ok(item)
     }
 
+    /// Download the file for a release into `path`, returning the path of the
+    /// downloaded file. Its name is made safe and shortened to fit `layout`.
     pub fn download_item(
         &self,
         item: &DigitalItem,
         path: &str,
         audio_format: &str,
+        layout: &Layout,
         m: &indicatif::MultiProgress,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        let sanitiser = &layout.sanitiser();
         let download_url = &item
             .downloads
             .as_ref()
@@ -316,71 +389,170 @@ impl Api {
             .get(audio_format)
             .unwrap()
             .url;
-        let res = self.request(Method::GET, download_url)?;
-
-        let len = res.content_length().unwrap();
         let full_title = format!("{} - {}", item.title, item.artist);
         let pb = m.add(
-            indicatif::ProgressBar::new(len)
+            indicatif::ProgressBar::new(0)
                 .with_message(full_title.clone())
                 .with_style(
                     ProgressStyle::with_template("{bar:10} ({bytes}/{total_bytes}) {wide_msg}")
                         .unwrap(),
                 ),
         );
+        let mut attempt = 0;
+
+        let full_path = loop {
+            let res = self.request(RequestClass::Download, Method::GET, download_url)?;
+            let len = res.content_length().unwrap_or(0);
+            pb.set_length(len);
+            pb.set_position(0);
+
+            let filename = match res
+                .headers()
+                .get(CONTENT_DISPOSITION)
+                .and_then(|v| util::parse_content_disposition(v.as_bytes(), sanitiser))
+            {
+                Some(filename) => filename,
+                None => {
+                    let fallback = item.fallback_filename(audio_format, sanitiser);
+                    m.suspend(|| {
+                        warn!("Missing or invalid `Content-Disposition` for {full_title}, saving as `{fallback}`")
+                    });
+                    fallback
+                }
+            };
+            m.suspend(|| debug!("Downloading as `{filename}` to `{path}`"));
+
+            // TODO: drop file with `.part` extension instead, while downloading, and then rename when finished?.
+
+            let full_path =
+                Path::new(path).join(truncate_name(&filename, layout.max_name_bytes.into()));
+            let mut file = File::create(&full_path)?;
+            let mut stream = res;
+            m.suspend(|| debug!("Starting download"));
+
+            match util::copy_with_progress(
+                &mut stream,
+                &mut file,
+                &pb,
+                &self.transfer_limits,
+                self.bandwidth.as_ref(),
+            ) {
+                Ok(_) => {
+                    pb.set_position(len);
+                    break full_path;
+                }
+                Err(e) if attempt < MAX_RETRIES => {
+                    attempt += 1;
+                    m.suspend(|| {
+                        warn!("Download of {full_title} failed ({e}), retrying ({attempt}/{MAX_RETRIES})")
+                    });
+                }
+                Err(e) => {
+                    pb.finish_and_clear();
+                    drop(file);
+                    let _ = fs::remove_file(&full_path);
+                    bail!(format!(
+                        "gave up downloading {full_title} after {} attempts: {e}",
+                        attempt + 1
+                    ));
+                }
+            }
+        };
 
-        let disposition = res.headers().get(CONTENT_DISPOSITION);
+        pb.finish_and_clear();
+        m.suspend(|| debug!("Finished downloading {full_title}"));
 
-        if let None = disposition {
-            pb.finish_and_clear();
-            return Err(
-                format!("could not download {full_title} when using url `{download_url}`").into(),
-            );
+        Ok(full_path)
+    }
+
+    /// Download an image from Bandcamp's CDN to `destination`, via a temporary
+    /// file so a failed download doesn't leave a broken image behind.
+    pub fn download_image(&self, url: &str, destination: &Path) -> Result<(), Box<dyn Error>> {
+        let mut res = self.request(RequestClass::Download, Method::GET, url)?;
+        let mut partial = destination.as_os_str().to_owned();
+        partial.push(".part");
+
+        let mut file = File::create(&partial)?;
+        if let Err(e) = res.copy_to(&mut file) {
+            drop(file);
+            let _ = fs::remove_file(&partial);
+            return Err(e.into());
         }
+        fs::rename(&partial, destination)?;
+
+        Ok(())
+    }
+
+    /// Fetch a release's public page, for details that aren't part of its
+    /// digital item, like its description and tags.
+    pub fn get_release_page(&self, url: &str) -> Result<ReleasePage, Box<dyn Error>> {
+        debug!("Retrieving release page {url}");
+        let text = self.request(RequestClass::Page, Method::GET, url)?.text()?;
+        let soup = Soup::new(&text);
+
+        let Some(blob) = soup
+            .attr_name("data-tralbum")
+            .find()
+            .and_then(|node| node.get("data-tralbum"))
+        else {
+            bail!(format!(
+                "could not find `data-tralbum` on release page {url}"
+            ));
+        };
 
-        // `HeaderValue::to_str` only handles valid ASCII bytes, and Bandcamp
-        // chooses to put Unicode into the content-disposition for some reason,
-        // so need to handle ourselves.
-        let content = str::from_utf8(disposition.unwrap().as_bytes())?;
-        // Should probably use a thing to properly parse the content of content disposition.
-        let filename = util::slice_string(
-            content
-                .split("; ")
-                .find(|x| x.starts_with("filename="))
-                .unwrap(),
-            9,
-        )
-        .trim_matches('"');
-        m.suspend(|| debug!("Downloading as `{filename}` to `{path}`"));
-
-        // TODO: drop file with `.part` extension instead, while downloading, and then rename when finished?.
-
-        let full_path = Path::new(path).join(filename);
-        let mut file = File::create(&full_path)?;
-        let mut stream = res;
-        m.suspend(|| debug!("Starting download"));
-
-        util::copy_with_progress(&mut stream, &mut file, &pb)?;
-        pb.set_position(len);
-
-        // Close downloaded file.
-        drop(file);
-
-        if !item.is_single() {
-            m.suspend(|| debug!("Unzipping album"));
-            let file = File::open(&full_path)?;
-            let reader = BufReader::new(file);
-            let mut archive = zip::ZipArchive::new(reader)?;
-
-            archive.extract(path)?;
-            fs::remove_file(&full_path)?;
-            m.suspend(|| debug!("Unzipped and removed original archive"));
+        let mut page = serde_json::from_str::<ReleasePage>(&blob)?;
+        page.tags = soup
+            .class("tag")
+            .find_all()
+            .map(|tag| tag.text().trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+
+        // Lyrics are in a row under each track on album pages, and on their own
+        // on track pages.
+        let single = page.trackinfo.len() == 1;
+        for track in page.trackinfo.iter_mut().filter(|t| t.lyrics.is_none()) {
+            let node = track
+                .track_num
+                .and_then(|number| {
+                    soup.attr("id", format!("lyrics_row_{number}"))
+                        .find()
+                        .and_then(|row| row.class("lyricsText").find())
+                })
+                .or_else(|| single.then(|| soup.class("lyricsText").find()).flatten());
+            track.lyrics = node
+                .map(|lyrics| text_with_breaks(&lyrics).trim().to_string())
+                .filter(|lyrics| !lyrics.is_empty());
         }
-        // Cover folder downloading for singles
 
-        pb.finish_and_clear();
-        m.println(format!("(Done) {full_title}"))?;
+        Ok(page)
+    }
+}
 
-        Ok(())
+/// Text of an element, keeping the line breaks that `<br>`s make.
+fn text_with_breaks<N: NodeExt + QueryBuilderExt>(node: &N) -> String {
+    let mut text = String::new();
+    let mut after_break = false;
+    for child in node.children() {
+        if child.name() == "br" {
+            text.push('\n');
+            after_break = true;
+            continue;
+        }
+
+        let child_text = if child.is_text() {
+            child.text()
+        } else {
+            text_with_breaks(&child)
+        };
+        // Don't double up breaks that are followed by a newline in the source.
+        if after_break {
+            text.push_str(child_text.strip_prefix('\n').unwrap_or(&child_text));
+        } else {
+            text.push_str(&child_text);
+        }
+        after_break = false;
     }
+
+    text
 }
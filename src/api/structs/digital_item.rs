@@ -1,20 +1,25 @@
-use crate::util::make_string_fs_safe;
+use crate::layout::{Layout, PathContext};
+use crate::sanitise::Sanitiser;
 
 use chrono::{Datelike, NaiveDateTime};
-use serde::{self, Deserialize};
+use serde::{self, Deserialize, Serialize};
 use std::{collections::HashMap, path::Path};
 
-const FORMAT: &str = "%d %b %Y %T %Z";
+/// Format of the dates Bandcamp gives us.
+pub const FORMAT: &str = "%d %b %Y %T %Z";
 
-// #[derive(Clone, Deserialize, Debug)]
-// #[serde(untagged)]
-// pub enum ArtId {
-//     Str(String),
-//     Num(i64),
-// }
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(untagged)]
+pub enum ArtId {
+    Str(String),
+    Num(i64),
+}
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct DigitalItem {
+    // Download URLs are temporary and tied to the user's account, so they
+    // aren't kept when saving an item.
+    #[serde(skip_serializing)]
     pub downloads: Option<HashMap<String, DigitalItemDownload>>,
     pub package_release_date: Option<String>,
     pub title: String,
@@ -22,7 +27,7 @@ pub struct DigitalItem {
     pub download_type: Option<String>,
     pub download_type_str: String,
     pub item_type: String,
-    // pub art_id: Option<ArtId>,
+    pub art_id: Option<ArtId>,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -33,11 +38,34 @@ pub struct DigitalItemDownload {
     pub url: String,
 }
 
+/// File extension used by Bandcamp for a single track in the given format.
+pub fn audio_extension(audio_format: &str) -> &'static str {
+    match audio_format {
+        "flac" => "flac",
+        "wav" => "wav",
+        "aac-hi" | "alac" => "m4a",
+        "mp3-320" | "mp3-v0" => "mp3",
+        "aiff-lossless" => "aiff",
+        "vorbis" => "ogg",
+        _ => "bin",
+    }
+}
+
 impl DigitalItem {
-    // pub fn cover_url(&self) -> String {
-    //     let art_id = &self.art_id;
-    //     format!("https://f4.bcbits.com/img/a{art_id}")
-    // }
+    /// URL of the release's cover art, in one of Bandcamp's image formats.
+    pub fn cover_url(&self, format: u8) -> Option<String> {
+        let art_id = match self.art_id.as_ref()? {
+            ArtId::Num(n) => *n,
+            ArtId::Str(s) => s.parse().ok()?,
+        };
+        if art_id <= 0 {
+            return None;
+        }
+
+        Some(format!(
+            "https://f4.bcbits.com/img/a{art_id:010}_{format}.jpg"
+        ))
+    }
 
     pub fn is_single(&self) -> bool {
         (self.download_type.is_some() && self.download_type.as_ref().unwrap() == "t")
@@ -45,27 +73,56 @@ impl DigitalItem {
             || self.item_type == "track"
     }
 
+    fn release_datetime(&self) -> Option<NaiveDateTime> {
+        let d = self.package_release_date.as_ref()?;
+        match NaiveDateTime::parse_from_str(d, FORMAT) {
+            Ok(dt) => Some(dt),
+            Err(err) => {
+                debug!("Failed to parse date time: {}", err);
+                None
+            }
+        }
+    }
+
     pub fn release_year(&self) -> String {
-        match &self.package_release_date {
-            Some(d) => match NaiveDateTime::parse_from_str(d, FORMAT) {
-                Ok(dt) => dt.and_utc().year().to_string(),
-                Err(err) => {
-                    debug!("Failed to parse date time: {}", err);
-                    String::from("0000")
-                },
-            },
+        match self.release_datetime() {
+            Some(dt) => dt.and_utc().year().to_string(),
             None => String::from("0000"),
         }
     }
 
-    pub fn destination_path<P: AsRef<Path>>(&self, root: P) -> String {
-        root.as_ref()
-            .join(make_string_fs_safe(&self.artist))
-            .join(format!(
-                "{} ({})",
-                make_string_fs_safe(&self.title),
-                self.release_year()
-            ))
+    /// Release date in `YYYY-MM-DD` format, if Bandcamp gives a valid one.
+    pub fn known_release_date(&self) -> Option<String> {
+        self.release_datetime()
+            .map(|dt| dt.format("%Y-%m-%d").to_string())
+    }
+
+    /// Release date in `YYYY-MM-DD` format, or `0000-00-00` if it's unknown.
+    pub fn release_date(&self) -> String {
+        self.known_release_date()
+            .unwrap_or_else(|| String::from("0000-00-00"))
+    }
+
+    /// Filename to save a download as when Bandcamp doesn't give us a usable
+    /// `Content-Disposition` header.
+    pub fn fallback_filename(&self, audio_format: &str, sanitiser: &Sanitiser) -> String {
+        let extension = if self.is_single() {
+            audio_extension(audio_format)
+        } else {
+            "zip"
+        };
+
+        sanitiser.sanitise(&format!("{} - {}.{extension}", self.artist, self.title))
+    }
+
+    pub fn destination_path<P: AsRef<Path>>(
+        &self,
+        root: P,
+        layout: &Layout,
+        ctx: &PathContext,
+    ) -> String {
+        layout
+            .release_path(root.as_ref(), self, ctx)
             .to_str()
             .unwrap()
             .to_owned()
@@ -1,11 +1,16 @@
+use chrono::NaiveDateTime;
 use serde::Deserialize;
 use serde_aux::prelude::deserialize_string_from_number;
 use std::collections::HashMap;
 
 pub mod digital_item;
+pub mod release_page;
 pub use crate::api::structs::digital_item::DigitalItem;
+pub use crate::api::structs::release_page::ReleasePage;
 
 pub type DownloadsMap = HashMap<String, String>;
+/// Collection items keyed by the same ID used in `DownloadsMap`.
+pub type ItemsMap = HashMap<String, Item>;
 
 // TODO: test with no hidden items, some hidden items, and no non-hidden items.
 /// Structure of the JSON blob extracted from a user's Bandcamp page.
@@ -25,11 +30,10 @@ pub struct ItemCache {
     pub collection: HashMap<String, Item>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug)]
 pub struct Item {
-    //     /// Used in collection_data.sequence, and tracklist. Probably the most unique field?
-    //     #[serde(deserialize_with = "deserialize_string_from_number")]
-    //     pub item_id: String,
+    /// Used in collection_data.sequence, and tracklist. Probably the most unique field?
+    pub item_id: Option<u64>,
     //     /// The type of the item: "album" or "track".
     //     pub item_type: String,
     /// Used in `id => download url` mapping.
@@ -40,6 +44,23 @@ pub struct Item {
     pub band_name: String,
     /// The name of the item.
     pub item_title: String,
+    /// The label that released the item, if it wasn't self-released.
+    pub label: Option<String>,
+    /// Public page for the item on Bandcamp.
+    pub item_url: Option<String>,
+    /// When the item was purchased, e.g. `28 Dec 2021 04:03:19 GMT`.
+    pub purchased: Option<String>,
+}
+
+impl Item {
+    pub fn purchased_datetime(&self) -> Option<NaiveDateTime> {
+        NaiveDateTime::parse_from_str(self.purchased.as_ref()?, digital_item::FORMAT).ok()
+    }
+
+    /// ID used in `id => download url` mapping.
+    pub fn download_id(&self) -> String {
+        format!("{}{}", self.sale_item_type, self.sale_item_id)
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+
+/// Structure of the `data-tralbum` JSON blob on a release's public page, along
+/// with the details that are only available in the page's HTML.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ReleasePage {
+    pub current: ReleaseInfo,
+    #[serde(default)]
+    pub trackinfo: Vec<TrackInfo>,
+    pub url: Option<String>,
+    /// Tags shown at the bottom of the page, which aren't in the blob.
+    #[serde(skip)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct ReleaseInfo {
+    /// The release's description.
+    pub about: Option<String>,
+    pub credits: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct TrackInfo {
+    pub track_num: Option<u64>,
+    pub title: Option<String>,
+    /// Length of the track in seconds.
+    pub duration: Option<f64>,
+    /// Filled in from the page's HTML when it's not in the blob.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lyrics: Option<String>,
+}
+
+impl ReleasePage {
+    /// Details of a track by its number. Releases with only one track might not
+    /// number it.
+    pub fn track(&self, number: u64) -> Option<&TrackInfo> {
+        let only = match self.trackinfo.as_slice() {
+            [only] if number == 1 => Some(only),
+            _ => None,
+        };
+
+        self.trackinfo
+            .iter()
+            .find(|t| t.track_num == Some(number))
+            .or(only)
+    }
+}
@@ -1,10 +1,78 @@
+use crate::{api::structs::DigitalItem, extract::Track};
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     error::Error,
     fs::{self, File},
     io::Write,
-    path::Path,
+    path::{Path, PathBuf},
 };
 
+/// Name of the cache file in the output folder, shared with
+/// Ezwen/bandcamp-collection-downloader.
+pub const CACHE_FILE: &str = "bandcamp-collection-downloader.cache";
+
+/// File next to the cache that records where each release was saved, as JSON
+/// lines. Later lines for the same ID replace earlier ones.
+const RELEASES_FILE: &str = "bandsnatch-releases.jsonl";
+
+/// Where a release was saved, and the information used to build its path.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Release {
+    pub id: String,
+    /// Folder the release was saved in, relative to the output folder.
+    pub path: PathBuf,
+    /// Files belonging to the release, relative to `path`.
+    #[serde(default)]
+    pub files: Vec<PathBuf>,
+    /// The release's tracks in order, with their files relative to `path`.
+    /// Kept so they're still known after `--track-template` renames them.
+    #[serde(default)]
+    pub tracks: Vec<Track>,
+    pub item: DigitalItem,
+    #[serde(default)]
+    pub label: Option<String>,
+    pub format: String,
+    /// Whether `path` is shared with other releases, like singles saved with
+    /// `--singles-template`, and only `files` belong to this release.
+    #[serde(default)]
+    pub shared: bool,
+    /// Artist and title shown in the user's collection when the release was
+    /// saved, to notice when they're changed on Bandcamp.
+    #[serde(default)]
+    pub collection_artist: Option<String>,
+    #[serde(default)]
+    pub collection_title: Option<String>,
+    /// When the release was purchased, as given by Bandcamp.
+    #[serde(default)]
+    pub purchased: Option<String>,
+}
+
+impl Release {
+    /// Files of the release that are still on disk, inside `root`.
+    pub fn files_on_disk(&self, root: &Path) -> Vec<PathBuf> {
+        let dir = root.join(&self.path);
+        self.files
+            .iter()
+            .map(|f| dir.join(f))
+            .filter(|f| f.exists())
+            .collect()
+    }
+
+    /// Recorded tracks of the release that are still on disk, inside `root`.
+    pub fn tracks_on_disk(&self, root: &Path) -> Vec<Track> {
+        let dir = root.join(&self.path);
+        self.tracks
+            .iter()
+            .map(|t| Track {
+                file: dir.join(&t.file),
+                ..t.clone()
+            })
+            .filter(|t| t.file.exists())
+            .collect()
+    }
+}
+
 /// Cache for already downloaded/skipped songs, following the format set by
 /// Ezwen/bandcamp-collection-downloaderr.
 pub struct Cache<P: AsRef<Path>> {
@@ -39,4 +107,41 @@ impl<P: AsRef<Path>> Cache<P> {
 
         Ok(())
     }
+
+    fn releases_path(&self) -> PathBuf {
+        self.path.as_ref().with_file_name(RELEASES_FILE)
+    }
+
+    /// Get the recorded location of every release, keyed by ID.
+    pub fn releases(&self) -> Result<HashMap<String, Release>, Box<dyn Error>> {
+        let Ok(content) = fs::read_to_string(self.releases_path()) else {
+            return Ok(HashMap::new());
+        };
+
+        let mut releases = HashMap::new();
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            match serde_json::from_str::<Release>(line) {
+                Ok(release) => {
+                    releases.insert(release.id.clone(), release);
+                }
+                Err(e) => warn!("Skipping unreadable line in {RELEASES_FILE}: {e}"),
+            }
+        }
+
+        Ok(releases)
+    }
+
+    /// Record where a release was saved.
+    pub fn add_release(&self, release: &Release) -> Result<(), Box<dyn Error>> {
+        let mut file = File::options()
+            .create(true)
+            .append(true)
+            .open(self.releases_path())?;
+        let mut line = serde_json::to_string(release)?;
+        line.push('\n');
+
+        file.write_all(line.as_bytes())?;
+
+        Ok(())
+    }
 }
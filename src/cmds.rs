@@ -1,3 +1,5 @@
+pub mod backfill;
 pub mod debug_collection;
 pub mod release;
+pub mod reorganize;
 pub mod run;
@@ -0,0 +1,369 @@
+use clap::{builder::PossibleValuesParser, Args as ClapArgs};
+use crossbeam_utils::thread;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    api::{
+        self,
+        structs::{DownloadsMap, ItemsMap},
+        Api,
+    },
+    cache::{self, Cache, Release},
+    cmds::run::{self, FORMATS},
+    cookies, enrich, extract,
+    layout::{Layout, PathClaims},
+    library, playlist, util,
+};
+
+/// File in the output folder listing the releases a backfill has finished, so
+/// it can pick up where it left off. Removed once every release is done.
+const PROGRESS_FILE: &str = "bandsnatch-backfill.progress";
+
+/// Add covers, tags, and other extras to releases that have already been
+/// downloaded, without downloading them again. Stopping partway through and
+/// running it again carries on from where it stopped.
+#[derive(Debug, ClapArgs)]
+pub struct Args {
+    /// The audio format releases were downloaded in. Only needed to find
+    /// releases downloaded by a version of Bandsnatch that didn't record where
+    /// they were saved.
+    #[arg(short = 'f', long = "format", value_parser = PossibleValuesParser::new(FORMATS), env = "BS_FORMAT")]
+    audio_format: Option<String>,
+
+    #[command(flatten)]
+    common: run::Common,
+
+    #[command(flatten)]
+    enrich: enrich::Enrich,
+
+    #[command(flatten)]
+    layout: Layout,
+
+    /// Start again from the first release, instead of carrying on from where
+    /// the last backfill stopped.
+    #[arg(long)]
+    restart: bool,
+}
+
+pub fn command(args: Args) -> Result<(), Box<dyn Error>> {
+    let cookies_file = args.common.cookies.as_ref().map(|p| {
+        let expanded = shellexpand::tilde(p);
+        expanded.into_owned()
+    });
+    let root = shellexpand::tilde(&args.common.output_folder);
+    let root = Path::new(root.as_ref());
+    if !root.is_dir() {
+        bail!("`{}` is not a folder", root.display());
+    }
+
+    let cache = Cache::new(root.join(cache::CACHE_FILE));
+    let progress = progress_cache(root, args.restart);
+    let (ids, done) = remaining(&cache, &progress)?;
+    let cache = Arc::new(Mutex::new(cache));
+    let progress = Arc::new(Mutex::new(progress));
+
+    if ids.is_empty() {
+        println!("Every downloaded release has already been backfilled");
+        return Ok(());
+    }
+    if done > 0 {
+        println!("Carrying on from the last backfill, {done} releases were already done");
+    }
+
+    let cookies = cookies::get_bandcamp_cookies(cookies_file.as_deref())?;
+    let api = Arc::new(Api::with_options(
+        cookies,
+        api::ApiOptions {
+            max_jobs: usize::from(args.common.jobs),
+            max_rate: args.common.rate_limit,
+            ..Default::default()
+        },
+    ));
+
+    let api::BandcampPage {
+        download_urls,
+        items: collection_items,
+    } = api.get_download_urls(&args.common.user, None, None)?;
+    let releases = cache.lock().unwrap().releases()?;
+    let claims = PathClaims::new(root, &releases);
+
+    println!("Backfilling {} releases", ids.len());
+
+    let queue = util::WorkQueue::from_vec(ids);
+    let m = Arc::new(MultiProgress::new());
+    let status = m
+        .add(ProgressBar::new_spinner().with_style(ProgressStyle::with_template("{msg}").unwrap()));
+    api.throttle.set_status_bar(status.clone());
+    let failures = Arc::new(Mutex::new(Vec::<String>::new()));
+
+    let ctx = Context {
+        root,
+        args: &args,
+        releases: &releases,
+        collection_items: &collection_items,
+        download_urls: &download_urls,
+        claims: &claims,
+    };
+
+    thread::scope(|scope| {
+        for i in 0..args.common.jobs {
+            let api = api.clone();
+            let cache = cache.clone();
+            let progress = progress.clone();
+            let queue = queue.clone();
+            let m = m.clone();
+            let failures = failures.clone();
+            let ctx = &ctx;
+
+            scope.spawn(move |_| {
+                while let Some(id) = queue.get_work() {
+                    m.suspend(|| debug!("backfiller {i} taking {id}"));
+
+                    match backfill(&api, ctx, &id, &m) {
+                        Ok(Some(Backfilled {
+                            release,
+                            failures: enrich_failures,
+                        })) => {
+                            // Files that were added are recorded either way, so
+                            // they're known about when retrying the rest.
+                            if let Err(e) = cache.lock().unwrap().add_release(&release) {
+                                m.suspend(|| warn!("Couldn't record the files of {id}: {e}"));
+                            }
+                            if !enrich_failures.is_empty() {
+                                let failed = enrich_failures.join(", ");
+                                failures.lock().unwrap().push(format!("{id}: {failed}"));
+                                continue;
+                            }
+
+                            let full_title =
+                                format!("{} - {}", release.item.title, release.item.artist);
+                            let _ = m.println(format!("(Done) {full_title}"));
+                        }
+                        // Releases that couldn't be found are looked for again
+                        // next time, as they may be found with other options.
+                        Ok(None) => continue,
+                        Err(e) => {
+                            m.suspend(|| warn!("Failed to backfill {id}: {e}"));
+                            failures.lock().unwrap().push(format!("{id}: {e}"));
+                            continue;
+                        }
+                    }
+
+                    if let Err(e) = progress.lock().unwrap().add(&id, "backfilled") {
+                        m.suspend(|| warn!("Couldn't record the progress of {id}: {e}"));
+                    }
+                }
+            });
+        }
+    })
+    .unwrap();
+    status.finish_and_clear();
+
+    if args.enrich.playlists {
+        let releases = cache.lock().unwrap().releases()?;
+        playlist::write_collection_playlists(root, &releases, &args.layout)?;
+    }
+
+    let failures = failures.lock().unwrap();
+    if !failures.is_empty() {
+        println!(
+            "Failed to backfill {} releases, run again to retry them:\n{}",
+            failures.len(),
+            failures.join("\n")
+        );
+        return Ok(());
+    }
+
+    // There's nothing to remove if every release was skipped.
+    let _ = fs::remove_file(root.join(PROGRESS_FILE));
+    println!("Finished!");
+
+    Ok(())
+}
+
+/// Where the releases a backfill has finished are recorded. Restarting forgets
+/// the ones from earlier backfills.
+fn progress_cache(root: &Path, restart: bool) -> Cache<PathBuf> {
+    let path = root.join(PROGRESS_FILE);
+    if restart {
+        let _ = fs::remove_file(&path);
+    }
+
+    Cache::new(path)
+}
+
+/// Releases that still need backfilling, along with how many an earlier
+/// backfill already finished.
+fn remaining(
+    cache: &Cache<PathBuf>,
+    progress: &Cache<PathBuf>,
+) -> Result<(Vec<String>, usize), Box<dyn Error>> {
+    let done = progress.content()?;
+    let mut ids = cache.content()?;
+    ids.sort();
+    ids.dedup();
+    ids.retain(|id| !done.contains(id));
+
+    Ok((ids, done.len()))
+}
+
+/// Everything shared between the backfill workers.
+struct Context<'a> {
+    root: &'a Path,
+    args: &'a Args,
+    releases: &'a HashMap<String, Release>,
+    collection_items: &'a ItemsMap,
+    download_urls: &'a DownloadsMap,
+    claims: &'a PathClaims,
+}
+
+/// A release that extras have been added to.
+struct Backfilled {
+    /// The release's updated record.
+    release: Release,
+    /// Extras that couldn't be added.
+    failures: Vec<String>,
+}
+
+/// Add extras to a single release, or `None` if it isn't on disk.
+fn backfill(
+    api: &Api,
+    ctx: &Context,
+    id: &str,
+    m: &MultiProgress,
+) -> Result<Option<Backfilled>, Box<dyn Error>> {
+    let Some(mut release) = find_release(api, ctx, id, m)? else {
+        return Ok(None);
+    };
+
+    let layout = &ctx.args.layout;
+    let dir = ctx.root.join(&release.path);
+    let files = release.files_on_disk(ctx.root);
+
+    let collection = ctx.collection_items.get(id);
+    let page = enrich::release_page(api, id, collection, &ctx.args.enrich, layout, m);
+    // Releases recorded before their tracks were have them worked out again.
+    let tracks = if release.tracks.is_empty() {
+        extract::identify_tracks(&files, &release.item, page.as_ref(), layout)
+    } else {
+        release.tracks_on_disk(ctx.root)
+    };
+    let added = enrich::enrich_release(
+        api,
+        &enrich::SavedRelease {
+            id,
+            dir: &dir,
+            files: &files,
+            tracks: &tracks,
+            item: &release.item,
+            collection,
+            shared: release.shared,
+            artist_dir: layout.artist_folder(&dir, &release.item),
+            page: page.as_ref(),
+        },
+        &ctx.args.enrich,
+        m,
+    );
+    // Files recorded before that had gone missing are saved again, and are
+    // already in the record.
+    for file in added.files.iter().filter_map(|f| f.strip_prefix(&dir).ok()) {
+        if !release.files.iter().any(|f| f == file) {
+            release.files.push(file.to_path_buf());
+        }
+    }
+
+    if release.tracks.is_empty() {
+        release.tracks = tracks.iter().filter_map(|t| t.relative_to(&dir)).collect();
+    }
+    if let Some(item) = ctx.collection_items.get(id) {
+        release.collection_artist = Some(item.band_name.clone());
+        release.collection_title = Some(item.item_title.clone());
+        release.purchased = item.purchased.clone();
+    }
+
+    Ok(Some(Backfilled {
+        release,
+        failures: added.failures,
+    }))
+}
+
+/// Find where a release was saved, from its record if it has one, or by working
+/// out where it would have been saved from its digital item.
+fn find_release(
+    api: &Api,
+    ctx: &Context,
+    id: &str,
+    m: &MultiProgress,
+) -> Result<Option<Release>, Box<dyn Error>> {
+    if let Some(release) = ctx.releases.get(id) {
+        if !ctx.root.join(&release.path).is_dir() {
+            m.suspend(|| {
+                warn!(
+                    "`{}` ({id}) no longer exists, skipping",
+                    release.path.display()
+                )
+            });
+            return Ok(None);
+        }
+        return Ok(Some(release.clone()));
+    }
+
+    let Some(audio_format) = &ctx.args.audio_format else {
+        m.suspend(|| {
+            warn!("{id} doesn't have a recorded folder, run with `--format` to look for it")
+        });
+        return Ok(None);
+    };
+
+    library::Unrecorded {
+        root: ctx.root,
+        layout: &ctx.args.layout,
+        audio_format,
+        download_urls: ctx.download_urls,
+        collection_items: ctx.collection_items,
+        claims: ctx.claims,
+        debug: ctx.args.common.debug,
+    }
+    .find(api, id, m)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn carries_on_from_where_it_stopped() {
+        let root = temp_dir("backfill-progress");
+        let cache = Cache::new(root.join(cache::CACHE_FILE));
+        for id in ["a2", "a1", "t3", "a1"] {
+            cache.add(id, "downloaded").unwrap();
+        }
+
+        let progress = progress_cache(&root, false);
+        assert_eq!(
+            remaining(&cache, &progress).unwrap(),
+            (vec!["a1".into(), "a2".into(), "t3".into()], 0)
+        );
+
+        progress.add("a2", "backfilled").unwrap();
+        let progress = progress_cache(&root, false);
+        assert_eq!(
+            remaining(&cache, &progress).unwrap(),
+            (vec!["a1".into(), "t3".into()], 1)
+        );
+
+        let progress = progress_cache(&root, true);
+        assert!(!root.join(PROGRESS_FILE).exists());
+        assert_eq!(remaining(&cache, &progress).unwrap().0.len(), 3);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -45,7 +45,7 @@ pub fn command(
 
     let body = api
         .client
-        .get(&format!("https://bandcamp.com/{user}"))
+        .get(format!("https://bandcamp.com/{user}"))
         .send()?
         .text()?;
     let soup = Soup::new(&body);
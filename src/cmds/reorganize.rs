@@ -0,0 +1,435 @@
+use clap::{builder::PossibleValuesParser, Args as ClapArgs};
+use indicatif::MultiProgress;
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    api::{self, Api},
+    cache::{self, Cache, Release},
+    cmds::run::FORMATS,
+    cookies,
+    layout::{self, Layout, PathClaims, PathContext},
+    library::{self, Journal, JournalEntry},
+    playlist,
+};
+
+/// Move releases that have already been downloaded into the folders they'd be
+/// saved to with the current layout options, without downloading them again.
+/// Releases downloaded by a version of Bandsnatch that didn't record where they
+/// were saved are looked up on Bandcamp when given a user and `--format`.
+#[derive(Debug, ClapArgs)]
+pub struct Args {
+    /// The audio format releases were downloaded in. Only needed to find
+    /// releases downloaded by a version of Bandsnatch that didn't record where
+    /// they were saved.
+    #[arg(short = 'f', long = "format", value_parser = PossibleValuesParser::new(FORMATS), env = "BS_FORMAT")]
+    audio_format: Option<String>,
+
+    #[arg(short, long, value_name = "COOKIES_FILE", env = "BS_COOKIES")]
+    cookies: Option<String>,
+
+    /// Enables some extra debug output in certain scenarios.
+    #[arg(long, env = "BS_DEBUG")]
+    debug: bool,
+
+    /// Show what would be moved, without actually moving anything.
+    #[arg(short = 'd', long = "dry-run")]
+    dry_run: bool,
+
+    #[command(flatten)]
+    layout: Layout,
+
+    /// The folder releases were downloaded to.
+    #[arg(
+        short,
+        long = "output-folder",
+        value_name = "FOLDER",
+        default_value = "./",
+        env = "BS_OUTPUT_FOLDER"
+    )]
+    output_folder: String,
+
+    /// Undo the moves made by the last reorganisation.
+    #[arg(long)]
+    rollback: bool,
+
+    /// Name of the user the releases were downloaded from (must be logged in
+    /// through cookies). Only needed with `--format`, to find releases that
+    /// weren't recorded.
+    #[clap(env = "BS_USER")]
+    user: Option<String>,
+}
+
+/// A release that needs to be moved.
+struct Pending {
+    release: Release,
+    to: PathBuf,
+    /// Whether the old or new folder is shared with other releases, so only
+    /// the release's own files should be moved.
+    shared: bool,
+}
+
+pub fn command(args: Args) -> Result<(), Box<dyn Error>> {
+    let root = shellexpand::tilde(&args.output_folder);
+    let root = Path::new(root.as_ref());
+    if !root.is_dir() {
+        bail!("`{}` is not a folder", root.display());
+    }
+
+    let cache = Cache::new(root.join(cache::CACHE_FILE));
+    let journal = Journal::new(root);
+
+    if args.rollback {
+        return rollback(root, &cache, &journal, &args.layout, args.dry_run);
+    }
+
+    if journal.exists() && !args.dry_run {
+        bail!(
+            "A previous reorganisation can still be rolled back. Run with `--rollback` to undo it, or delete `{}` to keep it.",
+            journal.path().display()
+        );
+    }
+
+    let mut releases = cache.releases()?;
+    let mut unrecorded = cache
+        .content()?
+        .into_iter()
+        .filter(|id| !releases.contains_key(id))
+        .collect::<Vec<_>>();
+    unrecorded.sort();
+    unrecorded.dedup();
+    if !unrecorded.is_empty() {
+        match (&args.user, &args.audio_format) {
+            (Some(user), Some(audio_format)) => {
+                let found = find_unrecorded(root, &args, user, audio_format, &releases, &unrecorded)?;
+                println!(
+                    "Found {} of {} releases without a recorded folder",
+                    found.len(),
+                    unrecorded.len()
+                );
+                for release in found {
+                    // Recorded right away, as this is where they are whether
+                    // they're moved or not.
+                    if !args.dry_run {
+                        cache.add_release(&release)?;
+                    }
+                    releases.insert(release.id.clone(), release);
+                }
+            }
+            _ => warn!(
+                "{} downloaded releases don't have a recorded folder and will be left alone. Run with your user name and `--format` to look for them.",
+                unrecorded.len()
+            ),
+        }
+    }
+
+    let mut releases = releases.into_values().collect::<Vec<_>>();
+    releases.sort_by(|a, b| a.id.cmp(&b.id));
+
+    // Start with no claims, as every release is getting a new folder.
+    let claims = PathClaims::for_moves(root);
+    let mut pending = vec![];
+    for mut release in releases {
+        let ctx = PathContext {
+            id: &release.id,
+            label: release.label.as_deref(),
+            format: &release.format,
+        };
+        let to = release
+            .item
+            .destination_path(root, &args.layout, &ctx)
+            .into();
+        let to = claims.claim(&args.layout, &release.id, &release.item, to);
+        let to = to.strip_prefix(root).unwrap_or(&to).to_path_buf();
+
+        let shared = args.layout.is_shared(&release.item);
+
+        if to == release.path {
+            if release.shared != shared && !args.dry_run {
+                release.shared = shared;
+                cache.add_release(&release)?;
+            }
+            continue;
+        }
+        if !root.join(&release.path).exists() {
+            warn!(
+                "`{}` ({}) no longer exists, skipping",
+                release.path.display(),
+                release.id
+            );
+            continue;
+        }
+
+        pending.push(Pending {
+            release,
+            to,
+            shared,
+        });
+    }
+
+    if pending.is_empty() {
+        println!("Everything is already in the right place");
+        return Ok(());
+    }
+
+    let layout = &args.layout;
+    // A release can't be moved into a folder that another release is still
+    // waiting to be moved out of, or has failed to move out of, so keep going
+    // until nothing else can move.
+    let mut moved = 0;
+    let mut stuck = HashSet::new();
+    loop {
+        let occupied = pending
+            .iter()
+            .map(|p| layout::path_key(&p.release.path))
+            .chain(stuck.iter().cloned())
+            .collect::<HashSet<_>>();
+        let (ready, waiting): (Vec<_>, Vec<_>) = pending.into_iter().partition(|p| {
+            let key = layout::path_key(&p.to);
+            p.shared || key == layout::path_key(&p.release.path) || !occupied.contains(&key)
+        });
+        pending = waiting;
+
+        if ready.is_empty() {
+            break;
+        }
+
+        for pending in ready {
+            let key = layout::path_key(&pending.release.path);
+            if reorganize(root, &cache, &journal, layout, pending, args.dry_run) {
+                moved += 1;
+            } else {
+                stuck.insert(key);
+            }
+        }
+    }
+
+    for Pending { release, to, .. } in &pending {
+        warn!(
+            "Couldn't move `{}` to `{}`, as it's still being used by another release",
+            release.path.display(),
+            to.display()
+        );
+    }
+
+    if args.dry_run {
+        println!("{moved} releases would be moved");
+    } else {
+        update_playlists(root, &cache, layout)?;
+        println!("Moved {moved} releases, run with `--rollback` to undo this");
+    }
+
+    Ok(())
+}
+
+/// Look up releases without a recorded folder on Bandcamp, and find where they
+/// were saved.
+fn find_unrecorded(
+    root: &Path,
+    args: &Args,
+    user: &str,
+    audio_format: &str,
+    releases: &HashMap<String, Release>,
+    ids: &[String],
+) -> Result<Vec<Release>, Box<dyn Error>> {
+    let cookies_file = args
+        .cookies
+        .as_ref()
+        .map(|p| shellexpand::tilde(p).into_owned());
+    let api = Api::new(cookies::get_bandcamp_cookies(cookies_file.as_deref())?);
+    let api::BandcampPage {
+        download_urls,
+        items: collection_items,
+    } = api.get_download_urls(user, None, None)?;
+
+    let layout = Layout::legacy();
+    let claims = PathClaims::new(root, releases);
+    let unrecorded = library::Unrecorded {
+        root,
+        layout: &layout,
+        audio_format,
+        download_urls: &download_urls,
+        collection_items: &collection_items,
+        claims: &claims,
+        debug: args.debug,
+    };
+
+    let m = MultiProgress::new();
+    let mut found = vec![];
+    for id in ids {
+        match unrecorded.find(&api, id, &m) {
+            Ok(Some(mut release)) => {
+                if let Some(item) = collection_items.get(id) {
+                    release.collection_artist = Some(item.band_name.clone());
+                    release.collection_title = Some(item.item_title.clone());
+                    release.purchased = item.purchased.clone();
+                }
+                found.push(release);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Couldn't look for {id}: {e}; skipped."),
+        }
+    }
+
+    Ok(found)
+}
+
+/// Regenerate the collection-wide playlists, if there are any, so they point
+/// at where releases were moved to.
+fn update_playlists(
+    root: &Path,
+    cache: &Cache<PathBuf>,
+    layout: &Layout,
+) -> Result<(), Box<dyn Error>> {
+    if root.join(playlist::PLAYLISTS_FOLDER).is_dir() {
+        playlist::write_collection_playlists(root, &cache.releases()?, layout)?;
+    }
+
+    Ok(())
+}
+
+/// Move a single release to its new folder, returning whether it was moved.
+fn reorganize(
+    root: &Path,
+    cache: &Cache<PathBuf>,
+    journal: &Journal,
+    layout: &Layout,
+    Pending {
+        mut release,
+        to,
+        shared,
+    }: Pending,
+    dry_run: bool,
+) -> bool {
+    let from = release.path.clone();
+    let to = to.as_path();
+    let plan = match library::plan_release_move(root, &release, to, shared) {
+        Ok(plan) => plan,
+        Err(e) => {
+            warn!("Couldn't read `{}`: {e}; skipped.", from.display());
+            return false;
+        }
+    };
+
+    let merging =
+        root.join(to).exists() && !library::is_same_file(&root.join(&from), &root.join(to));
+    println!(
+        "{} -> {}{}",
+        from.display(),
+        to.display(),
+        if merging { " (merging)" } else { "" }
+    );
+    for conflict in &plan.conflicts {
+        warn!(
+            "`{}` already exists in `{}` and will be left in place",
+            conflict.display(),
+            to.display()
+        );
+    }
+
+    if dry_run {
+        return true;
+    }
+
+    let entry = JournalEntry {
+        id: release.id.clone(),
+        from: from.clone(),
+        to: to.to_path_buf(),
+        moves: plan.moves,
+        shared: release.shared,
+    };
+    if let Err(e) = journal.record(&entry) {
+        warn!("Couldn't write to the journal: {e}; skipped.");
+        return false;
+    }
+
+    if let Err(e) = library::apply_moves(root, &entry.moves) {
+        warn!(
+            "Failed moving `{}` to `{}`: {e}; skipped. Run with `--rollback` to undo any partial moves.",
+            from.display(),
+            to.display()
+        );
+        return false;
+    }
+    let dir = root.join(to);
+    let artist_dir = layout.artist_folder(&dir, &release.item);
+    if let Err(e) = library::move_artist_nfo(root, &from, artist_dir) {
+        warn!("Couldn't move the artist.nfo for {}: {e}", release.id);
+    }
+    library::remove_empty_dirs(root, &from);
+    if shared && !release.shared && root.join(&from).is_dir() {
+        warn!(
+            "Files in `{}` that weren't downloaded with {} were left in place",
+            from.display(),
+            release.id
+        );
+    }
+
+    release.path = to.to_path_buf();
+    release.shared = shared;
+    if let Err(e) = cache.add_release(&release) {
+        warn!("Couldn't record the new folder for {}: {e}", release.id);
+    }
+
+    true
+}
+
+/// Undo the moves recorded in the journal, newest first.
+fn rollback(
+    root: &Path,
+    cache: &Cache<PathBuf>,
+    journal: &Journal,
+    layout: &Layout,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    if !journal.exists() {
+        println!("Nothing to roll back");
+        return Ok(());
+    }
+
+    let mut releases = cache.releases()?;
+    let mut failed = false;
+    for entry in journal.entries()?.into_iter().rev() {
+        println!("{} -> {}", entry.to.display(), entry.from.display());
+        if dry_run {
+            continue;
+        }
+
+        if let Err(e) = library::undo_moves(root, &entry.moves) {
+            warn!(
+                "Failed moving `{}` back to `{}`: {e}",
+                entry.to.display(),
+                entry.from.display()
+            );
+            failed = true;
+            continue;
+        }
+        library::remove_empty_dirs(root, &entry.to);
+
+        if let Some(release) = releases.get_mut(&entry.id) {
+            release.path = entry.from;
+            release.shared = entry.shared;
+            cache.add_release(release)?;
+        }
+    }
+
+    if dry_run {
+        return Ok(());
+    }
+
+    if failed {
+        bail!(
+            "Some releases couldn't be moved back, see `{}` for the full list of moves",
+            journal.path().display()
+        );
+    }
+
+    journal.remove()?;
+    update_playlists(root, cache, layout)?;
+    println!("Rolled back the last reorganisation");
+
+    Ok(())
+}
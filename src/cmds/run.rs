@@ -1,15 +1,28 @@
-use clap::{builder::PossibleValuesParser, Args as ClapArgs};
+use clap::{builder::PossibleValuesParser, value_parser, Args as ClapArgs, ValueEnum};
 use crossbeam_utils::thread;
-use indicatif::MultiProgress;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use std::{
+    collections::HashMap,
+    error::Error,
     fs,
-    path::Path,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use crate::{api, cache, cookies, util};
+use crate::{
+    api::{
+        self,
+        structs::{DigitalItem, ItemsMap},
+    },
+    cache, cookies, enrich, extract,
+    layout::{self, Layout, PathClaims, PathContext},
+    library, playlist, throttle, util,
+};
 
-const FORMATS: &[&str] = &[
+/// Audio formats that releases can be downloaded in.
+pub const FORMATS: &[&str] = &[
     "flac",
     "wav",
     "aac-hi",
@@ -20,6 +33,10 @@ const FORMATS: &[&str] = &[
     "alac",
 ];
 
+/// Prefix for the folder a single is downloaded to before being moved into a
+/// folder shared with other singles, so it can't overwrite any of their files.
+const STAGING_PREFIX: &str = ".bandsnatch-";
+
 macro_rules! skip_err {
     ($res:expr) => {
         match $res {
@@ -32,6 +49,84 @@ macro_rules! skip_err {
     };
 }
 
+/// What to do with downloaded releases that have been renamed on Bandcamp.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnRename {
+    /// Warn about the release, leaving it where it is.
+    #[default]
+    Report,
+    /// Move the release to the folder matching its new name.
+    Rename,
+}
+
+/// A release whose digital item page has been fetched, ready to download.
+struct Resolved {
+    id: String,
+    item: DigitalItem,
+}
+
+/// What's needed to check downloaded releases for renames on Bandcamp.
+#[derive(Clone, Copy)]
+struct RenameCheck<'a> {
+    root: &'a Path,
+    layout: &'a Layout,
+    on_rename: OnRename,
+    dry_run: bool,
+    cache: &'a cache::Cache<PathBuf>,
+    collection_items: &'a ItemsMap,
+    claims: &'a PathClaims,
+}
+
+/// A release whose file has been downloaded, ready to extract and cache.
+struct Downloaded {
+    id: String,
+    item: DigitalItem,
+    path: String,
+    file: PathBuf,
+}
+
+/// Options shared by every command that works with a user's collection.
+#[derive(Debug, ClapArgs)]
+pub struct Common {
+    #[arg(short, long, value_name = "COOKIES_FILE", env = "BS_COOKIES")]
+    pub cookies: Option<String>,
+
+    /// Enables some extra debug output in certain scenarios.
+    #[arg(long, env = "BS_DEBUG")]
+    pub debug: bool,
+
+    /// The amount of parallel jobs (threads) to use. This is lowered
+    /// automatically while Bandcamp is throttling requests.
+    #[arg(short, long, default_value_t = 4, value_parser = value_parser!(u8).range(1..), env = "BS_JOBS")]
+    pub jobs: u8,
+
+    /// The folder releases are downloaded to.
+    #[arg(
+        short,
+        long = "output-folder",
+        value_name = "FOLDER",
+        default_value = "./",
+        env = "BS_OUTPUT_FOLDER"
+    )]
+    pub output_folder: String,
+
+    /// Maximum amount of page requests per second to send to Bandcamp. This is
+    /// lowered automatically while Bandcamp is throttling requests.
+    #[arg(
+        long,
+        value_name = "REQUESTS",
+        default_value_t = 3.0,
+        value_parser = throttle::parse_rate,
+        env = "BS_RATE_LIMIT"
+    )]
+    pub rate_limit: f64,
+
+    /// Name of the user whose collection releases are downloaded from (must be
+    /// logged in through cookies).
+    #[clap(env = "BS_USER")]
+    pub user: String,
+}
+
 #[derive(Debug, ClapArgs)]
 pub struct Args {
     #[arg(long, env = "BS_ALBUM")]
@@ -40,57 +135,102 @@ pub struct Args {
     #[arg(long, env = "BS_ARTIST")]
     artist: Option<String>,
 
+    #[command(flatten)]
+    common: Common,
+
     /// The audio format to download the files in.
     #[arg(short = 'f', long = "format", value_parser = PossibleValuesParser::new(FORMATS), env = "BS_FORMAT")]
     audio_format: String,
 
-    #[arg(short, long, value_name = "COOKIES_FILE", env = "BS_COOKIES")]
-    cookies: Option<String>,
-
-    /// Enables some extra debug output in certain scenarios.
-    #[arg(long, env = "BS_DEBUG")]
-    debug: bool,
-
     /// Return a list of all tracks to be downloaded, without actually downloading them.
     #[arg(short = 'd', long = "dry-run")]
     dry_run: bool,
 
+    /// The amount of threads used to extract downloaded releases.
+    #[arg(long, default_value_t = 2, value_parser = value_parser!(u8).range(1..), env = "BS_EXTRACT_JOBS")]
+    extract_jobs: u8,
+
+    #[command(flatten)]
+    enrich: enrich::Enrich,
+
+    #[command(flatten)]
+    extras: extract::Extras,
+
     /// Ignores any found cache file and instead does a from-scratch download run.
     #[arg(short = 'F', long, env = "BS_FORCE")]
     force: bool,
 
-    /// The amount of parallel jobs (threads) to use.
-    #[arg(short, long, default_value_t = 4, env = "BS_JOBS")]
-    jobs: u8,
-
     /// Maximum number of releases to download. Useful for testing.
     #[arg(short = 'n', long, env = "BS_LIMIT")]
     limit: Option<usize>,
 
-    /// The folder to extract downloaded releases to.
+    #[command(flatten)]
+    layout: Layout,
+
+    /// Maximum combined speed of all downloads in KiB/s, which has to be at
+    /// least `--min-speed` for each of the `--jobs`. Downloads are otherwise
+    /// not rate limited.
+    #[arg(long, value_name = "KIB_PER_SEC", env = "BS_MAX_DOWNLOAD_SPEED")]
+    max_download_speed: Option<NonZeroU32>,
+
+    /// Minimum download speed in KiB/s. Transfers slower than this for longer
+    /// than `--stall-timeout` are aborted and retried.
     #[arg(
-        short,
-        long = "output-folder",
-        value_name = "FOLDER",
-        default_value = "./",
-        env = "BS_OUTPUT_FOLDER"
+        long,
+        value_name = "KIB_PER_SEC",
+        default_value_t = 1,
+        env = "BS_MIN_SPEED"
     )]
-    output_folder: String,
+    min_speed: u64,
 
-    /// Name of the user to download releases from (must be logged in through cookies).
-    #[clap(env = "BS_USER")]
-    user: String,
+    /// What to do with downloaded releases whose artist, title, or label has
+    /// changed on Bandcamp. They're never downloaded again into a second
+    /// folder either way.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "ACTION",
+        default_value_t,
+        env = "BS_ON_RENAME"
+    )]
+    on_rename: OnRename,
+
+    /// The amount of threads used to fetch release information ahead of
+    /// downloading.
+    #[arg(long, default_value_t = 2, value_parser = value_parser!(u8).range(1..), env = "BS_RESOLVE_JOBS")]
+    resolve_jobs: u8,
+
+    /// How many seconds a download can stall (or stay below `--min-speed`)
+    /// before it is retried.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 30,
+        env = "BS_STALL_TIMEOUT"
+    )]
+    stall_timeout: u64,
 }
 
 pub fn command(args: Args) -> Result<(), Box<dyn std::error::Error>> {
-    let cookies_file = args.cookies.map(|p| {
+    let cookies_file = args.common.cookies.map(|p| {
         let expanded = shellexpand::tilde(&p);
         expanded.into_owned()
     });
-    let root = shellexpand::tilde(&args.output_folder);
+    let root = shellexpand::tilde(&args.common.output_folder);
     let root = Path::new(root.as_ref());
     let limit = args.limit.unwrap_or(usize::MAX);
 
+    // Each download only gets its share of the total speed, and would
+    // otherwise be aborted for being slower than `--min-speed`.
+    if let Some(max_speed) = args.max_download_speed {
+        let needed = args.min_speed * u64::from(args.common.jobs);
+        if u64::from(max_speed.get()) < needed {
+            bail!(
+                "`--max-download-speed` has to be at least `--min-speed` times `--jobs` ({needed} KiB/s), or every download would stall"
+            );
+        }
+    }
+
     let root_exists = match fs::metadata(root) {
         Ok(d) => Some(d.is_dir()),
         Err(_) => None,
@@ -106,14 +246,38 @@ pub fn command(args: Args) -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let cookies = cookies::get_bandcamp_cookies(cookies_file.as_deref())?;
-    let api = Arc::new(api::Api::new(cookies));
-    let cache = Arc::new(Mutex::new(cache::Cache::new(
-        root.join("bandcamp-collection-downloader.cache"),
-    )));
-
-    let download_urls = api
-        .get_download_urls(&args.user, args.artist.as_ref(), args.album.as_ref())?
-        .download_urls;
+    let api = Arc::new(api::Api::with_options(
+        cookies,
+        api::ApiOptions {
+            transfer_limits: util::TransferLimits {
+                stall_timeout: Duration::from_secs(args.stall_timeout.max(1)),
+                min_speed: args.min_speed * 1024,
+            },
+            max_jobs: usize::from(args.common.jobs),
+            max_rate: args.common.rate_limit,
+            max_download_speed: args.max_download_speed,
+        },
+    ));
+    let cache = Arc::new(Mutex::new(cache::Cache::new(root.join(cache::CACHE_FILE))));
+
+    let api::BandcampPage {
+        download_urls,
+        items: collection_items,
+    } = api.get_download_urls(&args.common.user, args.artist.as_ref(), args.album.as_ref())?;
+    let mut releases = cache.lock().unwrap().releases()?;
+    let claims = PathClaims::new(root, &releases);
+    check_renames(
+        &RenameCheck {
+            root,
+            layout: &args.layout,
+            on_rename: args.on_rename,
+            dry_run: args.dry_run,
+            cache: &cache.lock().unwrap(),
+            collection_items: &collection_items,
+            claims: &claims,
+        },
+        &mut releases,
+    )?;
     let items = {
         // Lock gets freed after this block.
         let cache_content = cache.lock().unwrap().content()?;
@@ -133,24 +297,35 @@ pub fn command(args: Args) -> Result<(), Box<dyn std::error::Error>> {
 
     let queue = util::WorkQueue::from_vec(items);
     let m = Arc::new(MultiProgress::new());
+    let status = m
+        .add(ProgressBar::new_spinner().with_style(ProgressStyle::with_template("{msg}").unwrap()));
+    api.throttle.set_status_bar(status.clone());
     let dry_run_results = Arc::new(Mutex::new(Vec::<String>::new()));
+    let failures = Arc::new(Mutex::new(Vec::<String>::new()));
+
+    // Releases flow through three pools: resolving digital item pages,
+    // downloading files, and extracting + caching. The bounded channels let
+    // resolvers work ahead of the downloaders without racing too far ahead.
+    let (resolved_tx, resolved_rx) =
+        crossbeam_channel::bounded::<Resolved>(usize::from(args.common.jobs) * 2);
+    let (downloaded_tx, downloaded_rx) =
+        crossbeam_channel::bounded::<Downloaded>(usize::from(args.extract_jobs) * 2);
 
     thread::scope(|scope| {
-        for i in 0..args.jobs {
+        for i in 0..args.resolve_jobs {
             let api = api.clone();
             let cache = cache.clone();
             let m = m.clone();
             let queue = queue.clone();
-            let audio_format = args.audio_format.clone();
             let dry_run_results = dry_run_results.clone();
+            let resolved_tx = resolved_tx.clone();
 
-            // somehow re-create thread if it panics
             scope.spawn(move |_| {
                 while let Some((id, url)) = queue.get_work() {
-                    m.suspend(|| debug!("thread {i} taking {id}"));
+                    m.suspend(|| debug!("resolver {i} taking {id}"));
 
                     // skip_err!
-                    let item = match api.get_digital_item(&url, &args.debug) {
+                    let item = match api.get_digital_item(&url, &args.common.debug) {
                         Ok(Some(item)) => item,
                         Ok(None) => {
                             let cache = cache.lock().unwrap();
@@ -161,7 +336,7 @@ pub fn command(args: Args) -> Result<(), Box<dyn std::error::Error>> {
                         Err(_) => continue,
                     };
 
-                    if let None = item.downloads {
+                    if item.downloads.is_none() {
                         let cache = cache.lock().unwrap();
                         warn!("Skipping {id}, does not have any downloads");
                         skip_err!(cache.add(&id, "No downloads"));
@@ -178,23 +353,257 @@ pub fn command(args: Args) -> Result<(), Box<dyn std::error::Error>> {
                         continue;
                     }
 
+                    // Only fails if every downloader has gone away.
+                    if resolved_tx.send(Resolved { id, item }).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        for i in 0..args.common.jobs {
+            let api = api.clone();
+            let collection_items = &collection_items;
+            let layout = &args.layout;
+            let claims = &claims;
+            let releases = &releases;
+            let m = m.clone();
+            let audio_format = args.audio_format.clone();
+            let failures = failures.clone();
+            let resolved_rx = resolved_rx.clone();
+            let downloaded_tx = downloaded_tx.clone();
+
+            // somehow re-create thread if it panics
+            scope.spawn(move |_| {
+                for Resolved { id, item } in resolved_rx.iter() {
+                    m.suspend(|| debug!("downloader {i} taking {id}"));
+
                     // TODO: intialise progressbar with this, and then pass that + m to download
-                    m.println(format!(
+                    // Failing to print isn't worth stopping the download for.
+                    let _ = m.println(format!(
                         "Trying {id}, {} - {} ({:?})",
                         item.title,
                         item.artist,
                         item.is_single(),
-                    ))
-                    .unwrap();
+                    ));
+
+                    let label = collection_items.get(&id).and_then(|i| i.label.as_deref());
+                    let path = match releases.get(&id) {
+                        // Releases that were already downloaded go back where
+                        // they were, even if they've been renamed since, so
+                        // they're never duplicated.
+                        Some(release)
+                            if release.shared == layout.is_shared(&item)
+                                && root.join(&release.path).is_dir() =>
+                        {
+                            root.join(&release.path).to_str().unwrap().to_owned()
+                        }
+                        _ => {
+                            let path = item.destination_path(
+                                root,
+                                layout,
+                                &PathContext {
+                                    id: &id,
+                                    label,
+                                    format: &audio_format,
+                                },
+                            );
+                            claims
+                                .claim(layout, &id, &item, path.into())
+                                .to_str()
+                                .unwrap()
+                                .to_owned()
+                        }
+                    };
+                    let download_path = if layout.is_shared(&item) {
+                        Path::new(&path)
+                            .join(format!("{STAGING_PREFIX}{id}"))
+                            .to_str()
+                            .unwrap()
+                            .to_owned()
+                    } else {
+                        path.clone()
+                    };
+                    skip_err!(fs::create_dir_all(&download_path));
 
-                    let path = item.destination_path(root);
-                    skip_err!(fs::create_dir_all(&path));
+                    // Held until the download finishes, so Bandcamp throttling
+                    // can lower how many downloads run at once.
+                    let slot = api.throttle.acquire();
 
                     // TODO: separate cache for failed downloads.
-                    // TODO: retries
-                    skip_err!(api.download_item(&item, &path, &audio_format, &m));
+                    let file =
+                        match api.download_item(&item, &download_path, &audio_format, layout, &m) {
+                            Ok(file) => file,
+                            Err(e) => {
+                                m.suspend(|| warn!("Failed to download {id}: {e}"));
+                                failures
+                                    .lock()
+                                    .unwrap()
+                                    .push(format!("{id}, {} - {}: {e}", item.title, item.artist));
+                                if download_path != path {
+                                    let _ = fs::remove_dir(&download_path);
+                                }
+                                continue;
+                            }
+                        };
+                    drop(slot);
+
+                    let downloaded = Downloaded {
+                        id,
+                        item,
+                        path,
+                        file,
+                    };
+                    if downloaded_tx.send(downloaded).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        for i in 0..args.extract_jobs {
+            let api = api.clone();
+            let cache = cache.clone();
+            let enrich = &args.enrich;
+            let extras = &args.extras;
+            let releases = &releases;
+            let collection_items = &collection_items;
+            let layout = &args.layout;
+            let audio_format = args.audio_format.clone();
+            let m = m.clone();
+            let failures = failures.clone();
+            let downloaded_rx = downloaded_rx.clone();
+
+            scope.spawn(move |_| {
+                for Downloaded {
+                    id,
+                    item,
+                    path,
+                    file,
+                } in downloaded_rx.iter()
+                {
+                    m.suspend(|| debug!("extractor {i} taking {id}"));
+                    let full_title = format!("{} - {}", item.title, item.artist);
+                    let label = collection_items.get(&id).and_then(|i| i.label.as_deref());
+
+                    let files = if layout.is_shared(&item) {
+                        // Replace the files from an earlier download of this
+                        // release, rather than keeping both.
+                        if let Some(previous) = releases.get(&id).filter(|r| r.shared) {
+                            for previous_file in &previous.files {
+                                let _ =
+                                    fs::remove_file(root.join(&previous.path).join(previous_file));
+                            }
+                        }
+                        match extract::place_shared_file(&file, Path::new(&path), &id, layout) {
+                            Ok(file) => vec![file],
+                            Err(e) => {
+                                m.suspend(|| warn!("Failed to move {id} into place: {e}"));
+                                failures
+                                    .lock()
+                                    .unwrap()
+                                    .push(format!("{id}, {full_title}: {e}"));
+                                // Don't leave the download behind in its staging folder.
+                                if let Some(staging) = file.parent() {
+                                    let _ = fs::remove_dir_all(staging);
+                                }
+                                continue;
+                            }
+                        }
+                    } else if item.is_single() {
+                        vec![file]
+                    } else {
+                        m.suspend(|| debug!("Unzipping {full_title}"));
+                        let files =
+                            match extract::extract_archive(&file, Path::new(&path), layout, extras)
+                            {
+                                Ok(files) => files,
+                                Err(e) => {
+                                    m.suspend(|| warn!("Failed to extract {id}: {e}"));
+                                    failures
+                                        .lock()
+                                        .unwrap()
+                                        .push(format!("{id}, {full_title}: {e}"));
+                                    continue;
+                                }
+                            };
+                        skip_err!(fs::remove_file(&file));
+                        m.suspend(|| debug!("Unzipped and removed original archive"));
+                        files
+                    };
+                    let page = enrich::release_page(
+                        &api,
+                        &id,
+                        collection_items.get(&id),
+                        enrich,
+                        layout,
+                        &m,
+                    );
+                    let tracks = extract::identify_tracks(&files, &item, page.as_ref(), layout);
+                    let originals = files.clone();
+                    let mut files = m.suspend(|| {
+                        layout.transliterate_files(extract::rename_tracks(
+                            files, &tracks, &item, layout,
+                        ))
+                    });
+                    let tracks = extract::follow_renames(tracks, &originals, &files);
+
+                    if layout.ascii {
+                        let ctx = PathContext {
+                            id: &id,
+                            label,
+                            format: &audio_format,
+                        };
+                        let mut names = layout.original_release_names(&item, &ctx);
+                        names.extend(originals.iter().zip(&files).filter_map(|(from, to)| {
+                            let from = from.file_name()?.to_string_lossy().into_owned();
+                            let to = to.file_name()?.to_string_lossy().into_owned();
+                            (from != to).then_some((to, from))
+                        }));
+                        skip_err!(layout::write_original_names(Path::new(&path), &names));
+                    }
+
+                    let added = enrich::enrich_release(
+                        &api,
+                        &enrich::SavedRelease {
+                            id: &id,
+                            dir: Path::new(&path),
+                            files: &files,
+                            tracks: &tracks,
+                            item: &item,
+                            collection: collection_items.get(&id),
+                            shared: layout.is_shared(&item),
+                            artist_dir: layout.artist_folder(Path::new(&path), &item),
+                            page: page.as_ref(),
+                        },
+                        enrich,
+                        &m,
+                    );
+                    files.extend(added.files);
+
+                    let _ = m.println(format!("(Done) {full_title}"));
+
+                    let path = Path::new(&path);
+                    let release = cache::Release {
+                        id: id.clone(),
+                        path: path.strip_prefix(root).unwrap_or(path).to_path_buf(),
+                        files: files
+                            .iter()
+                            .filter_map(|f| f.strip_prefix(path).ok())
+                            .map(Path::to_path_buf)
+                            .collect(),
+                        tracks: tracks.iter().filter_map(|t| t.relative_to(path)).collect(),
+                        item: item.clone(),
+                        label: label.map(str::to_string),
+                        format: audio_format.clone(),
+                        shared: layout.is_shared(&item),
+                        collection_artist: collection_items.get(&id).map(|i| i.band_name.clone()),
+                        collection_title: collection_items.get(&id).map(|i| i.item_title.clone()),
+                        purchased: collection_items.get(&id).and_then(|i| i.purchased.clone()),
+                    };
 
                     let cache = cache.lock().unwrap();
+                    skip_err!(cache.add_release(&release));
                     if !cache.content().unwrap().contains(&id) {
                         skip_err!(cache.add(
                             &id,
@@ -209,15 +618,157 @@ pub fn command(args: Args) -> Result<(), Box<dyn std::error::Error>> {
                 }
             });
         }
+
+        // Drop our own ends so each pool finishes once the one before it does.
+        drop(resolved_tx);
+        drop(resolved_rx);
+        drop(downloaded_tx);
+        drop(downloaded_rx);
     })
     .unwrap();
+    status.finish_and_clear();
 
     if args.dry_run {
         println!("{}", dry_run_results.lock().unwrap().join("\n"));
         return Ok(());
     }
 
+    let failures = failures.lock().unwrap();
+    if !failures.is_empty() {
+        println!(
+            "Failed to download {} releases, they will be retried on the next run:\n{}",
+            failures.len(),
+            failures.join("\n")
+        );
+    }
+
+    if args.enrich.playlists {
+        let releases = cache.lock().unwrap().releases()?;
+        playlist::write_collection_playlists(root, &releases, &args.layout)?;
+    }
+
     println!("Finished!");
 
     Ok(())
 }
+
+/// Look for downloaded releases whose artist, title, or label in the collection
+/// has changed since they were saved, and either report them or move them to
+/// the folder matching their new names.
+fn check_renames(
+    check: &RenameCheck,
+    releases: &mut HashMap<String, cache::Release>,
+) -> Result<(), Box<dyn Error>> {
+    let RenameCheck {
+        root,
+        layout,
+        on_rename,
+        dry_run,
+        cache,
+        collection_items,
+        claims,
+    } = *check;
+    let mut ids = releases.keys().cloned().collect::<Vec<_>>();
+    ids.sort();
+
+    for id in ids {
+        let (Some(release), Some(current)) = (releases.get_mut(&id), collection_items.get(&id))
+        else {
+            continue;
+        };
+
+        let (Some(artist), Some(title)) = (
+            release.collection_artist.clone(),
+            release.collection_title.clone(),
+        ) else {
+            // Releases saved before these were recorded take the current ones.
+            release.collection_artist = Some(current.band_name.clone());
+            release.collection_title = Some(current.item_title.clone());
+            release.purchased = current.purchased.clone();
+            if !dry_run {
+                cache.add_release(release)?;
+            }
+            continue;
+        };
+
+        if artist == current.band_name
+            && title == current.item_title
+            && release.label == current.label
+        {
+            continue;
+        }
+
+        // The digital item isn't fetched again, so carry the new names over
+        // to it where they match what the collection used to show.
+        let mut renamed = release.clone();
+        if renamed.item.artist == artist {
+            renamed.item.artist = current.band_name.clone();
+        }
+        if renamed.item.title == title {
+            renamed.item.title = current.item_title.clone();
+        }
+        renamed.label = current.label.clone();
+        renamed.collection_artist = Some(current.band_name.clone());
+        renamed.collection_title = Some(current.item_title.clone());
+
+        let ctx = PathContext {
+            id: &id,
+            label: renamed.label.as_deref(),
+            format: &renamed.format,
+        };
+        let to = renamed.item.destination_path(root, layout, &ctx);
+        // Only claim the new folder if the release is actually moving there.
+        let to = match on_rename {
+            OnRename::Report => claims.lookup(layout, &id, &renamed.item, to.into()),
+            OnRename::Rename => claims.claim(layout, &id, &renamed.item, to.into()),
+        };
+        let to = to.strip_prefix(root).unwrap_or(&to).to_path_buf();
+        let shared = layout.is_shared(&renamed.item);
+
+        if to == release.path {
+            if !dry_run {
+                cache.add_release(&renamed)?;
+            }
+            *release = renamed;
+            continue;
+        }
+
+        match on_rename {
+            OnRename::Report => warn!(
+                "{id} is now `{} - {}` on Bandcamp, but is saved in `{}`. Run with `--on-rename rename` to move it to `{}`.",
+                current.item_title,
+                current.band_name,
+                release.path.display(),
+                to.display()
+            ),
+            OnRename::Rename => {
+                println!(
+                    "Renaming {} -> {}",
+                    release.path.display(),
+                    to.display()
+                );
+                if dry_run {
+                    continue;
+                }
+
+                let plan = skip_err!(library::plan_release_move(root, release, &to, shared));
+                for conflict in &plan.conflicts {
+                    warn!(
+                        "`{}` already exists in `{}` and will be left in place",
+                        conflict.display(),
+                        to.display()
+                    );
+                }
+                skip_err!(library::apply_moves(root, &plan.moves));
+                library::remove_empty_dirs(root, &release.path);
+
+                renamed.path = to;
+                renamed.shared = shared;
+                cache.add_release(&renamed)?;
+                *release = renamed;
+            }
+        }
+    }
+
+    Ok(())
+}
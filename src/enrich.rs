@@ -0,0 +1,814 @@
+use clap::{value_parser, Args as ClapArgs, ValueEnum};
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, ImageFormat};
+use indicatif::MultiProgress;
+use lofty::picture::{MimeType, Picture, PictureType};
+use serde::Serialize;
+use std::{
+    error::Error,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    api::{
+        structs::{release_page::TrackInfo, DigitalItem, Item, ReleasePage},
+        Api,
+    },
+    extract::{self, Track},
+    layout::Layout,
+    nfo, playlist, replaygain,
+    tags::{self, Field},
+};
+
+/// Name of the metadata sidecar saved with each release for `--sidecar`.
+const SIDECAR_FILE: &str = "bandcamp.json";
+
+/// Format that lyrics are saved in for `--notes`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LyricsFormat {
+    /// Plain text.
+    #[default]
+    Txt,
+    /// An unsynchronised LRC file, which more players pick up automatically.
+    Lrc,
+}
+
+/// Size of the cover art downloaded for each release.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CoverSize {
+    /// The image as it was uploaded to Bandcamp, which can be very large.
+    Original,
+    /// 1200x1200.
+    #[default]
+    #[value(name = "1200")]
+    Large,
+    /// 700x700.
+    #[value(name = "700")]
+    Medium,
+    /// 350x350.
+    #[value(name = "350")]
+    Small,
+}
+
+impl CoverSize {
+    /// Bandcamp's ID for the image format.
+    fn format(&self) -> u8 {
+        match self {
+            Self::Original => 0,
+            Self::Large => 10,
+            Self::Medium => 16,
+            Self::Small => 2,
+        }
+    }
+}
+
+fn parse_cover_name(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.contains(['/', '\\']) || s.starts_with('.') {
+        return Err(format!("`{s}` isn't a valid file name"));
+    }
+
+    Ok(s.to_string())
+}
+
+/// Options for extra files and tags added to releases once they're saved.
+#[derive(Clone, Debug, ClapArgs)]
+pub struct Enrich {
+    /// File name to save each release's cover art as, like `cover.jpg` or
+    /// `folder.jpg`. Singles that share a folder get a cover named after their
+    /// track instead.
+    #[arg(
+        long,
+        value_name = "NAME",
+        default_value = "cover.jpg",
+        value_parser = parse_cover_name,
+        env = "BS_COVER_NAME"
+    )]
+    pub cover_name: String,
+
+    /// Size of the cover art to download.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "SIZE",
+        default_value_t,
+        env = "BS_COVER_SIZE"
+    )]
+    pub cover_size: CoverSize,
+
+    /// Don't download cover art for releases.
+    #[arg(long, env = "BS_NO_COVER")]
+    pub no_cover: bool,
+
+    /// Embed each release's cover art into its tracks, replacing any cover
+    /// they already have.
+    #[arg(long, env = "BS_EMBED_COVER")]
+    pub embed_cover: bool,
+
+    /// Largest width or height of embedded cover art in pixels. Larger covers
+    /// are shrunk to fit, so they don't bloat every track.
+    #[arg(
+        long,
+        value_name = "PIXELS",
+        default_value_t = 600,
+        value_parser = value_parser!(u32).range(16..),
+        env = "BS_EMBED_COVER_SIZE"
+    )]
+    pub embed_cover_size: u32,
+
+    /// Save each release's description and credits as `notes.txt` and
+    /// `credits.txt`, and the lyrics of each track next to it, named after the
+    /// track.
+    #[arg(long, env = "BS_NOTES")]
+    pub notes: bool,
+
+    /// Format to save lyrics in for `--notes`.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        default_value_t,
+        env = "BS_LYRICS_FORMAT"
+    )]
+    pub lyrics_format: LyricsFormat,
+
+    /// Write Kodi/Jellyfin style `album.nfo` files for each release, and
+    /// `artist.nfo` files for each artist if releases are saved in folders
+    /// named after them.
+    #[arg(long, env = "BS_NFO")]
+    pub nfo: bool,
+
+    /// Write an `.m3u8` playlist for each release, and keep collection-wide
+    /// playlists of everything, recent purchases, and each artist in a
+    /// `Playlists` folder.
+    #[arg(long, env = "BS_PLAYLISTS")]
+    pub playlists: bool,
+
+    /// Tag downloaded tracks with where they came from, as
+    /// `BANDCAMP_ITEM_ID`, `BANDCAMP_URL`, and `BANDCAMP_PURCHASE_DATE`, and
+    /// fill in their album artist if it's missing.
+    #[arg(long, env = "BS_PROVENANCE_TAGS")]
+    pub provenance_tags: bool,
+
+    /// Measure the loudness of each track and write ReplayGain tags, with the
+    /// album gain measured across the whole release. Releases that already have
+    /// them are skipped.
+    #[arg(long, env = "BS_REPLAYGAIN")]
+    pub replaygain: bool,
+
+    /// Save a `bandcamp.json` with each release, containing everything
+    /// Bandcamp knows about it, like its purchase date, tags, and track list.
+    #[arg(long, env = "BS_SIDECAR")]
+    pub sidecar: bool,
+}
+
+impl Enrich {
+    /// Whether the release's public page is needed, either for any of the
+    /// enabled extras or for its track list to name tracks with.
+    fn needs_page(&self, layout: &Layout) -> bool {
+        self.nfo || self.notes || self.sidecar || layout.track_template.is_some()
+    }
+}
+
+/// Fetch a release's public page if anything needs it. Failures are logged
+/// instead of being returned, as everything using the page can do without it.
+pub fn release_page(
+    api: &Api,
+    id: &str,
+    collection: Option<&Item>,
+    options: &Enrich,
+    layout: &Layout,
+    m: &MultiProgress,
+) -> Option<ReleasePage> {
+    if !options.needs_page(layout) {
+        return None;
+    }
+
+    let url = collection.and_then(|c| c.item_url.as_deref())?;
+    match api.get_release_page(url) {
+        Ok(page) => Some(page),
+        Err(e) => {
+            m.suspend(|| warn!("Failed to get the release page for {id}: {e}"));
+            None
+        }
+    }
+}
+
+/// A saved release that extra files and tags can be added to.
+pub struct SavedRelease<'a> {
+    pub id: &'a str,
+    /// Folder the release is saved in.
+    pub dir: &'a Path,
+    /// Files belonging to the release, inside `dir`.
+    pub files: &'a [PathBuf],
+    /// The release's tracks in order, with their files inside `dir`.
+    pub tracks: &'a [Track],
+    pub item: &'a DigitalItem,
+    /// The release as it's shown in the user's collection, if known.
+    pub collection: Option<&'a Item>,
+    /// Whether `dir` is shared with other releases.
+    pub shared: bool,
+    /// Folder named after the release's artist that `dir` is in, if any.
+    pub artist_dir: Option<&'a Path>,
+    /// The release's public page, if it was needed.
+    pub page: Option<&'a ReleasePage>,
+}
+
+impl SavedRelease<'_> {
+    /// Path to save a file for the whole release as. Releases sharing a folder
+    /// use the name of their first track followed by `shared_suffix` instead.
+    fn release_file(&self, name: &str, shared_suffix: &str) -> Option<PathBuf> {
+        if !self.shared {
+            return Some(self.dir.join(name));
+        }
+
+        let track = self.files.iter().find(|f| extract::is_audio(f))?;
+        let stem = track.file_stem()?.to_string_lossy();
+
+        Some(self.dir.join(format!("{stem}{shared_suffix}")))
+    }
+}
+
+/// Extras added to a release by [`enrich_release`].
+pub struct Enriched {
+    /// Paths of any new files.
+    pub files: Vec<PathBuf>,
+    /// Extras that couldn't be added. These shouldn't stop the release from
+    /// being recorded, so they're logged and carried on from.
+    pub failures: Vec<String>,
+}
+
+/// Add any enabled extras to a saved release.
+pub fn enrich_release(
+    api: &Api,
+    release: &SavedRelease,
+    options: &Enrich,
+    m: &MultiProgress,
+) -> Enriched {
+    let mut files = vec![];
+    let mut failures = vec![];
+    let mut fail = |what: &str, e: &dyn Display| {
+        m.suspend(|| warn!("Failed to {what} for {}: {e}", release.id));
+        failures.push(format!("failed to {what}: {e}"));
+    };
+    let mut cover = None;
+
+    if !options.no_cover {
+        match save_cover(api, release, options, m) {
+            Ok(Some(path)) => {
+                cover = Some(path.clone());
+                files.push(path);
+            }
+            Ok(None) => {}
+            Err(e) => fail("save the cover", &e),
+        }
+    }
+
+    if options.embed_cover {
+        if let Err(e) = embed_cover(api, release, cover.as_deref(), options, m) {
+            fail("embed the cover", &e);
+        }
+    }
+
+    if options.provenance_tags {
+        let fields = provenance_fields(release);
+        for file in release.files.iter().filter(|f| extract::is_audio(f)) {
+            if let Err(e) = tags::write_fields(file, &fields) {
+                fail(&format!("tag `{}`", file.display()), &e);
+            }
+        }
+    }
+
+    if options.replaygain {
+        if let Err(e) = apply_replaygain(release, m) {
+            fail("add ReplayGain tags", &e);
+        }
+    }
+
+    // A single track doesn't need its own playlist.
+    if options.playlists && !release.shared {
+        match playlist::write_release_playlist(release.dir, release.tracks, release.item) {
+            Ok(Some(playlist)) => files.push(playlist),
+            Ok(None) => {}
+            Err(e) => fail("write the playlist", &e),
+        }
+    }
+
+    let page = release.page;
+    if let (true, Some(page)) = (options.notes, page) {
+        match save_notes(release, page, options.lyrics_format, m) {
+            Ok(notes) => files.extend(notes),
+            Err(e) => fail("save the notes", &e),
+        }
+    }
+
+    if options.nfo {
+        let cover = cover
+            .as_ref()
+            .and_then(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned());
+        let url = release.collection.and_then(|c| c.item_url.as_deref());
+
+        // A folder of singles isn't an album.
+        if !release.shared {
+            let info = nfo::AlbumInfo {
+                item: release.item,
+                label: release.collection.and_then(|c| c.label.as_deref()),
+                url: url.or_else(|| page.and_then(|p| p.url.as_deref())),
+                cover: cover.as_deref(),
+                page,
+            };
+            match nfo::write_album_nfo(release.dir, &info) {
+                Ok(path) => files.push(path),
+                Err(e) => fail("write the nfo", &e),
+            }
+        }
+
+        if let Some(dir) = release.artist_dir {
+            if let Err(e) = nfo::write_artist_nfo(dir, &release.item.artist, url) {
+                fail("write the artist nfo", &e);
+            }
+        }
+    }
+
+    if options.sidecar {
+        match save_sidecar(release, page) {
+            Ok(Some(sidecar)) => files.push(sidecar),
+            Ok(None) => {}
+            Err(e) => fail("save the sidecar", &e),
+        }
+    }
+
+    files.retain(|f| !release.files.contains(f));
+    Enriched { files, failures }
+}
+
+/// Download a release's cover art, unless it's already been saved. Returns the
+/// path of the cover, if there is one.
+fn save_cover(
+    api: &Api,
+    release: &SavedRelease,
+    options: &Enrich,
+    m: &MultiProgress,
+) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    let extension = Path::new(&options.cover_name)
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let Some(path) = release.release_file(&options.cover_name, &extension) else {
+        return Ok(None);
+    };
+    if path.exists() {
+        return Ok(Some(path));
+    }
+
+    let Some(url) = release.item.cover_url(options.cover_size.format()) else {
+        m.suspend(|| debug!("{} doesn't have any cover art", release.id));
+        return Ok(None);
+    };
+
+    m.suspend(|| debug!("Downloading cover art for {} from {url}", release.id));
+    api.download_image(&url, &path)?;
+
+    Ok(Some(path))
+}
+
+/// Load a cover and shrink it to fit within `max_size`, re-encoding it as a
+/// JPEG if needed.
+fn cover_picture(path: &Path, max_size: u32) -> Result<Picture, Box<dyn Error>> {
+    let data = fs::read(path)?;
+    let format = image::guess_format(&data)?;
+    let image = image::load_from_memory_with_format(&data, format)?;
+
+    let mime = match format {
+        ImageFormat::Jpeg => Some(MimeType::Jpeg),
+        ImageFormat::Png => Some(MimeType::Png),
+        _ => None,
+    };
+    if let Some(mime) = mime {
+        if image.width() <= max_size && image.height() <= max_size {
+            return Ok(Picture::new_unchecked(
+                PictureType::CoverFront,
+                Some(mime),
+                None,
+                data,
+            ));
+        }
+    }
+
+    let image = image
+        .resize(max_size, max_size, FilterType::Lanczos3)
+        .into_rgb8();
+    let mut resized = vec![];
+    JpegEncoder::new_with_quality(&mut resized, 90).encode_image(&image)?;
+
+    Ok(Picture::new_unchecked(
+        PictureType::CoverFront,
+        Some(MimeType::Jpeg),
+        None,
+        resized,
+    ))
+}
+
+/// Embed a release's cover into each of its tracks. If the cover wasn't saved
+/// it's downloaded just for this.
+fn embed_cover(
+    api: &Api,
+    release: &SavedRelease,
+    saved: Option<&Path>,
+    options: &Enrich,
+    m: &MultiProgress,
+) -> Result<(), Box<dyn Error>> {
+    let picture = match saved {
+        Some(path) => cover_picture(path, options.embed_cover_size)?,
+        None => {
+            let Some(url) = release.item.cover_url(options.cover_size.format()) else {
+                return Ok(());
+            };
+            let path = release
+                .dir
+                .join(format!(".bandsnatch-cover-{}", release.id));
+            let picture = api
+                .download_image(&url, &path)
+                .and_then(|_| cover_picture(&path, options.embed_cover_size));
+            let _ = fs::remove_file(&path);
+            picture?
+        }
+    };
+
+    m.suspend(|| debug!("Embedding cover art into {}", release.id));
+    for file in release.files.iter().filter(|f| extract::is_audio(f)) {
+        tags::embed_cover(file, &picture)?;
+    }
+
+    Ok(())
+}
+
+/// Tags recording where a release came from on Bandcamp.
+fn provenance_fields(release: &SavedRelease) -> Vec<(Field, String)> {
+    let collection = release.collection;
+    let item_id = collection
+        .and_then(|c| c.item_id)
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| release.id.to_string());
+
+    let mut fields = vec![
+        (Field::Custom("BANDCAMP_ITEM_ID"), item_id),
+        (Field::AlbumArtist, release.item.artist.clone()),
+    ];
+    if let Some(url) = collection.and_then(|c| c.item_url.clone()) {
+        fields.push((Field::Custom("BANDCAMP_URL"), url));
+    }
+    if let Some(purchased) = collection.and_then(Item::purchased_datetime) {
+        fields.push((
+            Field::Custom("BANDCAMP_PURCHASE_DATE"),
+            purchased.format("%Y-%m-%d").to_string(),
+        ));
+    }
+
+    fields
+}
+
+/// Write text to a file, with Windows line endings swapped out for consistency.
+fn write_text(path: &Path, text: &str) -> Result<(), Box<dyn Error>> {
+    let mut text = text.trim().replace("\r\n", "\n");
+    text.push('\n');
+    fs::write(path, text)?;
+
+    Ok(())
+}
+
+/// Write a release's description, credits, and lyrics, replacing any older
+/// ones. Returns the paths of the files that were written.
+fn save_notes(
+    release: &SavedRelease,
+    page: &ReleasePage,
+    format: LyricsFormat,
+    m: &MultiProgress,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files = vec![];
+
+    let notes = [
+        ("notes.txt", page.current.about.as_deref()),
+        ("credits.txt", page.current.credits.as_deref()),
+    ];
+    for (name, text) in notes {
+        let Some(text) = text.filter(|t| !t.trim().is_empty()) else {
+            continue;
+        };
+        let Some(path) = release.release_file(name, &format!(".{name}")) else {
+            continue;
+        };
+        write_text(&path, text)?;
+        files.push(path);
+    }
+
+    let mut lyrics_saved = false;
+    for track in release.tracks {
+        let Some(lyrics) = page.track(track.number).and_then(|t| t.lyrics.as_deref()) else {
+            continue;
+        };
+        let file = &track.file;
+
+        let (path, text) = match format {
+            LyricsFormat::Txt => (file.with_extension("txt"), lyrics.to_string()),
+            LyricsFormat::Lrc => {
+                let header = format!(
+                    "[ar:{}]\n[al:{}]\n[ti:{}]\n\n",
+                    release.item.artist, release.item.title, track.title
+                );
+                (file.with_extension("lrc"), header + lyrics)
+            }
+        };
+        write_text(&path, &text)?;
+        files.push(path);
+        lyrics_saved = true;
+    }
+
+    if !lyrics_saved && page.trackinfo.iter().any(|t| t.lyrics.is_some()) {
+        m.suspend(|| {
+            warn!(
+                "{} has lyrics, but none of its tracks could be matched to them",
+                release.id
+            )
+        });
+    }
+
+    Ok(files)
+}
+
+/// Measure and tag the loudness of a release's tracks, unless they've already
+/// been tagged. Releases sharing a folder are measured on their own.
+fn apply_replaygain(release: &SavedRelease, m: &MultiProgress) -> Result<(), Box<dyn Error>> {
+    let tracks = release
+        .files
+        .iter()
+        .filter(|f| extract::is_audio(f))
+        .collect::<Vec<_>>();
+    if tracks.iter().all(|track| replaygain::has_album_gain(track)) {
+        return Ok(());
+    }
+
+    m.suspend(|| debug!("Measuring loudness of {}", release.id));
+    let analyses = tracks
+        .iter()
+        .map(|track| {
+            replaygain::analyse(track)
+                .map_err(|e| format!("couldn't decode `{}`: {e}", track.display()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (track, fields) in tracks.iter().zip(replaygain::release_fields(&analyses)?) {
+        if !fields.is_empty() {
+            tags::write_fields(track, &fields)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Contents of `bandcamp.json`.
+#[derive(Serialize)]
+struct Sidecar<'a> {
+    id: &'a str,
+    item_id: Option<u64>,
+    sale_item_id: Option<u64>,
+    sale_item_type: Option<&'a str>,
+    item_type: &'a str,
+    url: Option<&'a str>,
+    title: &'a str,
+    artist: &'a str,
+    label: Option<&'a str>,
+    /// In RFC 3339 format.
+    purchased: Option<String>,
+    /// In `YYYY-MM-DD` format.
+    release_date: Option<String>,
+    tags: &'a [String],
+    about: Option<&'a str>,
+    credits: Option<&'a str>,
+    tracks: &'a [TrackInfo],
+}
+
+/// Write `bandcamp.json` for a release, replacing any older one so it stays up
+/// to date.
+fn save_sidecar(
+    release: &SavedRelease,
+    page: Option<&ReleasePage>,
+) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    let Some(path) = release.release_file(SIDECAR_FILE, &format!(".{SIDECAR_FILE}")) else {
+        return Ok(None);
+    };
+
+    let collection = release.collection;
+    let sidecar = Sidecar {
+        id: release.id,
+        item_id: collection.and_then(|c| c.item_id),
+        sale_item_id: collection.map(|c| c.sale_item_id),
+        sale_item_type: collection.map(|c| c.sale_item_type.as_str()),
+        item_type: &release.item.item_type,
+        url: collection
+            .and_then(|c| c.item_url.as_deref())
+            .or_else(|| page.and_then(|p| p.url.as_deref())),
+        title: &release.item.title,
+        artist: &release.item.artist,
+        label: collection.and_then(|c| c.label.as_deref()),
+        purchased: collection
+            .and_then(Item::purchased_datetime)
+            .map(|dt| dt.and_utc().to_rfc3339()),
+        release_date: release.item.known_release_date(),
+        tags: page.map(|p| p.tags.as_slice()).unwrap_or_default(),
+        about: page.and_then(|p| p.current.about.as_deref()),
+        credits: page.and_then(|p| p.current.credits.as_deref()),
+        tracks: page.map(|p| p.trackinfo.as_slice()).unwrap_or_default(),
+    };
+
+    fs::write(&path, serde_json::to_string_pretty(&sidecar)?)?;
+
+    Ok(Some(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::structs::{digital_item::ArtId, release_page::ReleaseInfo},
+        test_util::{item, single, temp_dir},
+    };
+
+    fn saved<'a>(
+        dir: &'a Path,
+        files: &'a [PathBuf],
+        item: &'a DigitalItem,
+        shared: bool,
+    ) -> SavedRelease<'a> {
+        SavedRelease {
+            id: "a123",
+            dir,
+            files,
+            tracks: &[],
+            item,
+            collection: None,
+            shared,
+            artist_dir: None,
+            page: None,
+        }
+    }
+
+    #[test]
+    fn validates_cover_names() {
+        assert_eq!(parse_cover_name("folder.jpg").unwrap(), "folder.jpg");
+        for name in ["", "art/cover.jpg", "art\\cover.jpg", ".cover.jpg"] {
+            assert!(parse_cover_name(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn builds_cover_urls_for_each_size() {
+        let mut item = item("Artist", "Album");
+        assert_eq!(item.cover_url(CoverSize::Large.format()), None);
+
+        item.art_id = Some(ArtId::Num(1234));
+        assert_eq!(
+            item.cover_url(CoverSize::Large.format()).unwrap(),
+            "https://f4.bcbits.com/img/a0000001234_10.jpg"
+        );
+        item.art_id = Some(ArtId::Str("1234".to_string()));
+        assert_eq!(
+            item.cover_url(CoverSize::Original.format()).unwrap(),
+            "https://f4.bcbits.com/img/a0000001234_0.jpg"
+        );
+        item.art_id = Some(ArtId::Num(0));
+        assert_eq!(item.cover_url(CoverSize::Small.format()), None);
+    }
+
+    #[test]
+    fn names_covers_after_shared_tracks() {
+        let dir = Path::new("Artist");
+        let album = item("Artist", "Album");
+        let files = [dir.join("01 Intro.flac")];
+        assert_eq!(
+            saved(dir, &files, &album, false).release_file("cover.jpg", ".jpg"),
+            Some(dir.join("cover.jpg"))
+        );
+
+        let single = single("Artist", "Track");
+        let files = [dir.join("Artist - Track.flac")];
+        assert_eq!(
+            saved(dir, &files, &single, true).release_file("cover.jpg", ".jpg"),
+            Some(dir.join("Artist - Track.jpg"))
+        );
+        // Without a track there's nothing to name it after.
+        assert_eq!(
+            saved(dir, &[], &single, true).release_file("cover.jpg", ".jpg"),
+            None
+        );
+    }
+
+    #[test]
+    fn saves_everything_known_in_the_sidecar() {
+        let dir = temp_dir("enrich-sidecar");
+        let item = item("Artist", "Album");
+        let collection = Item {
+            item_id: Some(42),
+            sale_item_id: 123,
+            sale_item_type: "a".to_string(),
+            band_name: "Artist".to_string(),
+            item_title: "Album".to_string(),
+            label: Some("Label".to_string()),
+            item_url: Some("https://artist.bandcamp.com/album/album".to_string()),
+            purchased: Some("28 Dec 2021 04:03:19 GMT".to_string()),
+        };
+        let page = ReleasePage {
+            current: ReleaseInfo {
+                about: Some("About".to_string()),
+                credits: None,
+            },
+            trackinfo: vec![TrackInfo {
+                track_num: Some(1),
+                title: Some("Intro".to_string()),
+                duration: Some(61.5),
+                lyrics: None,
+            }],
+            url: None,
+            tags: vec!["rock".to_string()],
+        };
+        let release = SavedRelease {
+            collection: Some(&collection),
+            ..saved(&dir, &[], &item, false)
+        };
+
+        let path = save_sidecar(&release, Some(&page)).unwrap().unwrap();
+        assert_eq!(path, dir.join(SIDECAR_FILE));
+        let sidecar: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            sidecar,
+            serde_json::json!({
+                "id": "a123",
+                "item_id": 42,
+                "sale_item_id": 123,
+                "sale_item_type": "a",
+                "item_type": "album",
+                "url": "https://artist.bandcamp.com/album/album",
+                "title": "Album",
+                "artist": "Artist",
+                "label": "Label",
+                "purchased": "2021-12-28T04:03:19+00:00",
+                "release_date": "2021-03-02",
+                "tags": ["rock"],
+                "about": "About",
+                "credits": null,
+                "tracks": [{"track_num": 1, "title": "Intro", "duration": 61.5}],
+            })
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn names_sidecars_after_shared_tracks() {
+        let dir = temp_dir("enrich-shared-sidecar");
+        let item = single("Artist", "Track");
+        let files = [dir.join("Artist - Track.flac")];
+
+        let path = save_sidecar(&saved(&dir, &files, &item, true), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(path, dir.join("Artist - Track.bandcamp.json"));
+        let sidecar: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(sidecar["item_type"], "track");
+        assert_eq!(sidecar["url"], serde_json::Value::Null);
+        assert_eq!(sidecar["tracks"], serde_json::json!([]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_small_covers_as_they_are() {
+        let dir = temp_dir("enrich-small-cover");
+        let path = dir.join("cover.png");
+        image::RgbImage::new(16, 16).save(&path).unwrap();
+
+        let picture = cover_picture(&path, 16).unwrap();
+        assert_eq!(picture.mime_type(), Some(&MimeType::Png));
+        assert_eq!(picture.data(), fs::read(&path).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shrinks_large_covers_to_fit() {
+        let dir = temp_dir("enrich-large-cover");
+        let path = dir.join("cover.png");
+        image::RgbImage::new(64, 32).save(&path).unwrap();
+
+        let picture = cover_picture(&path, 16).unwrap();
+        assert_eq!(picture.pic_type(), PictureType::CoverFront);
+        assert_eq!(picture.mime_type(), Some(&MimeType::Jpeg));
+        let resized = image::load_from_memory(picture.data()).unwrap();
+        assert_eq!((resized.width(), resized.height()), (16, 8));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
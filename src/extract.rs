@@ -0,0 +1,724 @@
+use clap::{Args as ClapArgs, ValueEnum};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    api::structs::{DigitalItem, ReleasePage},
+    layout::Layout,
+    sanitise::truncate_name,
+};
+
+/// Extensions of the audio files Bandcamp gives us, in any format.
+pub const AUDIO_EXTENSIONS: &[&str] = &["flac", "wav", "m4a", "mp3", "aiff", "aif", "ogg"];
+
+/// A track of a release, and the file it's saved as.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Track {
+    pub number: u64,
+    pub title: String,
+    pub file: PathBuf,
+}
+
+impl Track {
+    /// The same track with its file relative to `dir`, if it's inside it.
+    pub fn relative_to(&self, dir: &Path) -> Option<Track> {
+        Some(Track {
+            file: self.file.strip_prefix(dir).ok()?.to_path_buf(),
+            ..self.clone()
+        })
+    }
+}
+
+/// Folder in each release that extras are moved to with `--extras folder`.
+const EXTRAS_FOLDER: &str = "Extras";
+
+/// What to do with files in release archives that aren't audio.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ExtrasMode {
+    /// Extract them where they are in the archive.
+    #[default]
+    Keep,
+    /// Move them into an `Extras` folder in the release.
+    Folder,
+    /// Don't extract them at all.
+    Skip,
+}
+
+fn parse_glob(s: &str) -> Result<Glob, String> {
+    GlobBuilder::new(s)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// Options for what's extracted from release archives, besides the audio.
+#[derive(Clone, Debug, ClapArgs)]
+pub struct Extras {
+    /// What to do with files in release archives that aren't audio, like PDFs,
+    /// videos, and artwork scans. The cover Bandcamp includes with every
+    /// release is always kept with the audio.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        default_value_t,
+        env = "BS_EXTRAS"
+    )]
+    pub extras: ExtrasMode,
+
+    /// Only extract extra files matching this glob, e.g. `*.pdf`. Matched
+    /// against the file's path in the archive, ignoring case. Can be given
+    /// multiple times.
+    #[arg(long = "include-extras", value_name = "GLOB", value_parser = parse_glob)]
+    pub include: Vec<Glob>,
+
+    /// Don't extract extra files matching this glob, e.g. `*.zip`. Takes
+    /// priority over `--include-extras`, and can be given multiple times.
+    #[arg(long = "exclude-extras", value_name = "GLOB", value_parser = parse_glob)]
+    pub exclude: Vec<Glob>,
+}
+
+/// Compiled `Extras` filters.
+struct ExtrasFilter {
+    mode: ExtrasMode,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl ExtrasFilter {
+    fn new(options: &Extras) -> Result<Self, Box<dyn Error>> {
+        let build = |globs: &[Glob]| {
+            globs
+                .iter()
+                .fold(GlobSetBuilder::new(), |mut set, glob| {
+                    set.add(glob.clone());
+                    set
+                })
+                .build()
+        };
+
+        Ok(Self {
+            mode: options.extras,
+            include: match options.include.as_slice() {
+                [] => None,
+                globs => Some(build(globs)?),
+            },
+            exclude: build(&options.exclude)?,
+        })
+    }
+
+    /// Where to extract an entry to, relative to the release's folder, or
+    /// `None` if it should be skipped.
+    fn route(&self, name: &Path) -> Option<PathBuf> {
+        if is_audio(name) || is_release_cover(name) {
+            return Some(name.to_path_buf());
+        }
+        if self.mode == ExtrasMode::Skip
+            || self.exclude.is_match(name)
+            || self.include.as_ref().is_some_and(|set| !set.is_match(name))
+        {
+            return None;
+        }
+
+        match self.mode {
+            ExtrasMode::Folder => Some(Path::new(EXTRAS_FOLDER).join(name)),
+            _ => Some(name.to_path_buf()),
+        }
+    }
+}
+
+/// Whether an archive entry is the cover Bandcamp puts in every release.
+fn is_release_cover(name: &Path) -> bool {
+    name.parent() == Some(Path::new(""))
+        && name
+            .file_stem()
+            .is_some_and(|stem| stem.eq_ignore_ascii_case("cover"))
+}
+
+/// Split a normalised archive entry name into its path components, or `None`
+/// if any of them could point outside of the folder it's extracted to.
+fn entry_components(name: &str) -> Option<Vec<&str>> {
+    name.split('/')
+        .map(|component| {
+            let mut parts = Path::new(component).components();
+            let safe = !component.is_empty()
+                && component != ".."
+                && !component.contains('\\')
+                && matches!(
+                    (parts.next(), parts.next()),
+                    (Some(Component::Normal(_)), None)
+                );
+            safe.then_some(component)
+        })
+        .collect()
+}
+
+pub fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| AUDIO_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+/// Extract a downloaded release archive into `destination`, returning the paths
+/// of the extracted files. Entry names are normalised and shortened following
+/// the layout options, and files that aren't audio are filtered and moved
+/// following the extras options.
+pub fn extract_archive(
+    archive: &Path,
+    destination: &Path,
+    layout: &Layout,
+    extras: &Extras,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let filter = ExtrasFilter::new(extras)?;
+    let file = File::open(archive)?;
+    let reader = BufReader::new(file);
+    let mut archive = zip::ZipArchive::new(reader)?;
+
+    let mut files = vec![];
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        // Folders are created as needed for the files in them, so they don't
+        // end up empty if everything in them is skipped or moved.
+        if entry.is_dir() {
+            continue;
+        }
+        // Names are checked after normalising them, as compatibility forms
+        // turn characters like `．` and `／` into `.` and `/`. Entries that
+        // would escape the destination are skipped.
+        let name = layout.normalisation.apply(entry.name());
+        let Some(components) = entry_components(&name) else {
+            continue;
+        };
+        let Some(name) = filter.route(&components.iter().collect::<PathBuf>()) else {
+            continue;
+        };
+        let path = name
+            .components()
+            .map(|c| {
+                truncate_name(
+                    &c.as_os_str().to_string_lossy(),
+                    layout.max_name_bytes.into(),
+                )
+            })
+            .fold(destination.to_path_buf(), |path, c| path.join(c));
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut out = File::create(&path)?;
+        io::copy(&mut entry, &mut out)?;
+        files.push(path);
+    }
+
+    Ok(files)
+}
+
+/// Move a downloaded single into a folder shared with other releases, adding
+/// the release's ID to its name if another release already has a file with the
+/// same name. The folder it was downloaded to is removed afterwards.
+pub fn place_shared_file(
+    file: &Path,
+    destination: &Path,
+    id: &str,
+    layout: &Layout,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let name = file
+        .file_name()
+        .ok_or("downloaded file has no name")?
+        .to_string_lossy()
+        .into_owned();
+
+    let mut target = destination.join(&name);
+    if target.exists() {
+        let (stem, extension) = match name.rsplit_once('.') {
+            Some((stem, extension)) => (stem, format!(".{extension}")),
+            None => (name.as_str(), String::new()),
+        };
+        let suffix = format!(" [{id}]{extension}");
+        let max = usize::from(layout.max_name_bytes).saturating_sub(suffix.len());
+        target = destination.join(format!("{}{suffix}", truncate_name(stem, max)));
+        if target.exists() {
+            bail!(format!(
+                "both `{name}` and `{}` already exist in `{}`",
+                target.file_name().unwrap_or_default().to_string_lossy(),
+                destination.display()
+            ));
+        }
+    }
+
+    fs::rename(file, &target)?;
+    if let Some(staging) = file.parent() {
+        let _ = fs::remove_dir(staging);
+    }
+
+    Ok(target)
+}
+
+/// Parse the track number and title out of the name Bandcamp gives a track,
+/// which is usually `Artist - Album - 01 Title.ext` for albums, or
+/// `Artist - Title.ext` for singles.
+pub fn parse_track(path: &Path, item: &DigitalItem) -> Option<Track> {
+    let stem = path.file_stem()?.to_str()?;
+
+    if item.is_single() {
+        return Some(Track {
+            number: 1,
+            title: item.title.clone(),
+            file: path.to_path_buf(),
+        });
+    }
+
+    let prefix = format!("{} - {} - ", item.artist, item.title);
+    let numbered = match stem.strip_prefix(&prefix) {
+        Some(rest) => rest,
+        // Bandcamp sometimes mangles characters in the prefix, so look for the
+        // first ` - NN ` instead.
+        None => {
+            let mut rest = None;
+            for (i, _) in stem.match_indices(" - ") {
+                let candidate = &stem[i + 3..];
+                if split_track_number(candidate).is_some() {
+                    rest = Some(candidate);
+                    break;
+                }
+            }
+            rest?
+        }
+    };
+
+    let (number, title) = split_track_number(numbered)?;
+    Some(Track {
+        number,
+        title: title.to_string(),
+        file: path.to_path_buf(),
+    })
+}
+
+/// Split `01 Title` into its number and title.
+pub fn split_track_number(s: &str) -> Option<(u64, &str)> {
+    let (number, title) = s.split_once(' ')?;
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) || title.is_empty() {
+        return None;
+    }
+
+    Some((number.parse().ok()?, title))
+}
+
+/// Work out which track each of a release's audio files is, in track order.
+/// When the release's track list is known, tracks take their titles from it,
+/// and files are matched to it by the number in the name Bandcamp gives them,
+/// or by the name `--track-template` would have given them. Otherwise tracks
+/// are parsed from their names, and only fall back to the order of the files
+/// if that fails.
+pub fn identify_tracks(
+    files: &[PathBuf],
+    item: &DigitalItem,
+    page: Option<&ReleasePage>,
+    layout: &Layout,
+) -> Vec<Track> {
+    let mut audio = files.iter().filter(|f| is_audio(f)).collect::<Vec<_>>();
+    audio.sort();
+
+    // Files that were already renamed can't be parsed, but can be recognised
+    // by comparing them to the name each track would be given.
+    let renamed_track = |file: &Path| {
+        let name = file.file_name()?.to_str()?;
+        page?.trackinfo.iter().enumerate().find_map(|(i, info)| {
+            let track = Track {
+                number: info.track_num.unwrap_or(i as u64 + 1),
+                title: info.title.clone()?,
+                file: file.to_path_buf(),
+            };
+            (layout.track_filename(item, &track)? == name).then_some(track.number)
+        })
+    };
+
+    let mut tracks = audio
+        .into_iter()
+        .enumerate()
+        .map(|(i, file)| {
+            let parsed = parse_track(file, item);
+            let number = parsed
+                .as_ref()
+                .map(|t| t.number)
+                .or_else(|| renamed_track(file))
+                .unwrap_or(i as u64 + 1);
+            let title = page
+                .and_then(|p| p.track(number))
+                .and_then(|t| t.title.clone())
+                .or(parsed.map(|t| t.title))
+                .or_else(|| file.file_stem().map(|s| s.to_string_lossy().into_owned()))
+                .unwrap_or_default();
+
+            Track {
+                number,
+                title,
+                file: file.clone(),
+            }
+        })
+        .collect::<Vec<_>>();
+    tracks.sort_by(|a, b| a.number.cmp(&b.number).then_with(|| a.file.cmp(&b.file)));
+
+    tracks
+}
+
+/// Rename a release's tracks using `--track-template`, returning the new paths
+/// of all the given files. Does nothing if no template is set.
+pub fn rename_tracks(
+    files: Vec<PathBuf>,
+    tracks: &[Track],
+    item: &DigitalItem,
+    layout: &Layout,
+) -> Vec<PathBuf> {
+    if layout.track_template.is_none() {
+        return files;
+    }
+
+    files
+        .into_iter()
+        .map(|file| {
+            let Some(track) = tracks.iter().find(|t| t.file == file) else {
+                return file;
+            };
+            let Some(name) = layout.track_filename(item, track) else {
+                return file;
+            };
+            let renamed = file.with_file_name(name);
+
+            if renamed == file {
+                return file;
+            }
+            if renamed.exists() {
+                warn!(
+                    "Not renaming `{}`, as `{}` already exists",
+                    file.display(),
+                    renamed.display()
+                );
+                return file;
+            }
+
+            match fs::rename(&file, &renamed) {
+                Ok(()) => renamed,
+                Err(e) => {
+                    warn!("Failed to rename `{}`: {e}", file.display());
+                    file
+                }
+            }
+        })
+        .collect()
+}
+
+/// Point tracks at the files they were renamed to, given a release's files
+/// before and after renaming them, in the same order.
+pub fn follow_renames(tracks: Vec<Track>, from: &[PathBuf], to: &[PathBuf]) -> Vec<Track> {
+    tracks
+        .into_iter()
+        .map(|track| match from.iter().position(|f| *f == track.file) {
+            Some(i) => Track {
+                file: to[i].clone(),
+                ..track
+            },
+            None => track,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{args, item, layout, single, temp_dir};
+    /// Write a zip with the given entry names, each containing its own name.
+    fn write_zip(path: &Path, names: &[&str]) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for name in names {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            io::Write::write_all(&mut zip, name.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    /// Every file under `dir`, relative to it.
+    fn files_in(dir: &Path) -> Vec<PathBuf> {
+        let mut files = vec![];
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(
+                    files_in(&path)
+                        .into_iter()
+                        .map(|f| Path::new(path.file_name().unwrap()).join(f)),
+                );
+            } else {
+                files.push(PathBuf::from(path.file_name().unwrap()));
+            }
+        }
+        files.sort();
+        files
+    }
+
+    fn page() -> ReleasePage {
+        serde_json::from_str(
+            r#"{
+                "current": {},
+                "trackinfo": [
+                    { "track_num": 1, "title": "First: Part 1" },
+                    { "track_num": 2, "title": "Second" },
+                    { "track_num": 10, "title": "Tenth" }
+                ]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn numbers_and_titles(tracks: &[Track]) -> Vec<(u64, &str, &str)> {
+        tracks
+            .iter()
+            .map(|t| {
+                (
+                    t.number,
+                    t.title.as_str(),
+                    t.file.file_name().unwrap().to_str().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rejects_unsafe_entry_names() {
+        assert_eq!(entry_components("a/b.flac"), Some(vec!["a", "b.flac"]));
+        assert_eq!(entry_components("a.b/..c"), Some(vec!["a.b", "..c"]));
+        for name in [
+            "../a.flac",
+            "a/../../b.flac",
+            "/a.flac",
+            "a//b.flac",
+            "a/",
+            "./a.flac",
+            "a\\..\\b.flac",
+            "",
+        ] {
+            assert_eq!(entry_components(name), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn extracts_crafted_archives_safely() {
+        // Full-width full stops and solidi only turn into `..` and `/` once
+        // they're normalised.
+        let names = [
+            "Artist - Album - 01 Song.flac",
+            "../escaped.flac",
+            "．．/escaped-nfkc.flac",
+            "a／．．／．．／escaped-solidus.flac",
+            "/absolute.flac",
+            "a\\..\\..\\escaped-backslash.flac",
+            "Scans/booklet.pdf",
+        ];
+
+        for form in ["none", "nfc", "nfd", "nfkc", "nfkd"] {
+            let dir = temp_dir(&format!("crafted-{form}"));
+            let archive = dir.join("release.zip");
+            write_zip(&archive, &names);
+            let destination = dir.join("Artist").join("Album");
+            fs::create_dir_all(&destination).unwrap();
+
+            let args = args(&["--normalize", form]);
+            let files =
+                extract_archive(&archive, &destination, &args.layout, &args.extras).unwrap();
+
+            assert!(
+                files.iter().all(|f| f.starts_with(&destination)),
+                "{form}: {files:?}"
+            );
+            let outside = files_in(&dir)
+                .into_iter()
+                .filter(|f| !f.starts_with("Artist/Album") && f != Path::new("release.zip"))
+                .collect::<Vec<_>>();
+            assert!(outside.is_empty(), "{form}: {outside:?}");
+
+            let extracted = files_in(&destination);
+            assert!(extracted.contains(&PathBuf::from("Artist - Album - 01 Song.flac")));
+            assert!(extracted.contains(&PathBuf::from("Scans/booklet.pdf")));
+            if form.starts_with("nfk") {
+                assert_eq!(extracted.len(), 2, "{form}: {extracted:?}");
+            }
+
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn splits_track_numbers() {
+        assert_eq!(split_track_number("01 Title"), Some((1, "Title")));
+        assert_eq!(split_track_number("120 A B"), Some((120, "A B")));
+        assert_eq!(split_track_number("01"), None);
+        assert_eq!(split_track_number("01 "), None);
+        assert_eq!(split_track_number(" Title"), None);
+        assert_eq!(split_track_number("1a Title"), None);
+        assert_eq!(split_track_number("-1 Title"), None);
+        assert_eq!(split_track_number("Title 01"), None);
+    }
+
+    #[test]
+    fn parses_album_tracks() {
+        let track = parse_track(
+            Path::new("Artist - Album - 03 Some - Song.flac"),
+            &item("Artist", "Album"),
+        );
+        assert_eq!(track.as_ref().map(|t| t.number), Some(3));
+        assert_eq!(track.map(|t| t.title).as_deref(), Some("Some - Song"));
+    }
+
+    #[test]
+    fn parses_tracks_with_mangled_prefixes() {
+        let track = parse_track(
+            Path::new("Artist꞉ X - Album - 07 Song.mp3"),
+            &item("Artist", "Album"),
+        );
+        assert_eq!(track.as_ref().map(|t| t.number), Some(7));
+        assert_eq!(track.map(|t| t.title).as_deref(), Some("Song"));
+    }
+
+    #[test]
+    fn parses_singles_from_their_item() {
+        let track = parse_track(
+            Path::new("Artist - Whatever.flac"),
+            &single("Artist", "Album"),
+        )
+        .unwrap();
+        assert_eq!((track.number, track.title.as_str()), (1, "Album"));
+    }
+
+    #[test]
+    fn doesnt_parse_unnumbered_tracks() {
+        assert!(parse_track(Path::new("Song.flac"), &item("Artist", "Album")).is_none());
+        assert!(parse_track(
+            Path::new("Artist - Album - Song.flac"),
+            &item("Artist", "Album")
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn identifies_tracks_from_the_track_list() {
+        let files = [
+            "Artist - Album - 10 Tenth.flac",
+            "Artist - Album - 02 Second.flac",
+            "Artist - Album - 01 First꞉ Part 1.flac",
+            "cover.jpg",
+        ]
+        .map(PathBuf::from);
+        let tracks = identify_tracks(
+            &files,
+            &item("Artist", "Album"),
+            Some(&page()),
+            &layout(&[]),
+        );
+        assert_eq!(
+            numbers_and_titles(&tracks),
+            [
+                (1, "First: Part 1", "Artist - Album - 01 First꞉ Part 1.flac"),
+                (2, "Second", "Artist - Album - 02 Second.flac"),
+                (10, "Tenth", "Artist - Album - 10 Tenth.flac"),
+            ]
+        );
+    }
+
+    #[test]
+    fn identifies_renamed_tracks() {
+        let layout = layout(&["--track-template", "{title} ({track}).{ext}"]);
+        let files = [
+            "Tenth (10).flac",
+            "First꞉ Part 1 (1).flac",
+            "Second (2).flac",
+        ]
+        .map(PathBuf::from);
+        let tracks = identify_tracks(&files, &item("Artist", "Album"), Some(&page()), &layout);
+        assert_eq!(
+            numbers_and_titles(&tracks),
+            [
+                (1, "First: Part 1", "First꞉ Part 1 (1).flac"),
+                (2, "Second", "Second (2).flac"),
+                (10, "Tenth", "Tenth (10).flac"),
+            ]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_file_order() {
+        let files = ["b.flac", "a.flac", "notes.txt"].map(PathBuf::from);
+        let tracks = identify_tracks(&files, &item("Artist", "Album"), None, &layout(&[]));
+        assert_eq!(
+            numbers_and_titles(&tracks),
+            [(1, "a", "a.flac"), (2, "b", "b.flac")]
+        );
+
+        // Titles still come from the track list when there is one.
+        let tracks = identify_tracks(
+            &files,
+            &item("Artist", "Album"),
+            Some(&page()),
+            &layout(&[]),
+        );
+        assert_eq!(
+            numbers_and_titles(&tracks),
+            [(1, "First: Part 1", "a.flac"), (2, "Second", "b.flac")]
+        );
+    }
+
+    #[test]
+    fn places_shared_files_without_overwriting() {
+        let dir = temp_dir("shared");
+        let layout = layout(&[]);
+        fs::create_dir_all(dir.join("staging")).unwrap();
+        fs::create_dir_all(dir.join("Singles")).unwrap();
+        fs::write(dir.join("Singles/Song.flac"), "other").unwrap();
+
+        fs::write(dir.join("staging/Song.flac"), "new").unwrap();
+        let placed = place_shared_file(
+            &dir.join("staging/Song.flac"),
+            &dir.join("Singles"),
+            "1",
+            &layout,
+        )
+        .unwrap();
+        assert_eq!(placed, dir.join("Singles/Song [1].flac"));
+
+        // Both names are taken now, so nothing is moved.
+        fs::create_dir_all(dir.join("staging")).unwrap();
+        fs::write(dir.join("staging/Song.flac"), "newer").unwrap();
+        assert!(place_shared_file(
+            &dir.join("staging/Song.flac"),
+            &dir.join("Singles"),
+            "1",
+            &layout
+        )
+        .is_err());
+        assert_eq!(
+            fs::read_to_string(dir.join("Singles/Song.flac")).unwrap(),
+            "other"
+        );
+        assert_eq!(fs::read_to_string(&placed).unwrap(), "new");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn follows_tracks_to_renamed_files() {
+        let from = ["b.flac", "a.flac", "cover.jpg"].map(PathBuf::from);
+        let to = ["02 B.flac", "01 A.flac", "cover.jpg"].map(PathBuf::from);
+        let tracks = identify_tracks(&from, &item("Artist", "Album"), None, &layout(&[]));
+        let tracks = follow_renames(tracks, &from, &to);
+        assert_eq!(
+            numbers_and_titles(&tracks),
+            [(1, "a", "01 A.flac"), (2, "b", "02 B.flac")]
+        );
+    }
+}
@@ -0,0 +1,848 @@
+use crate::{
+    api::structs::DigitalItem,
+    cache::Release,
+    extract::{self, Track},
+    sanitise::{truncate_name, FsProfile, Normalisation, Replacement, Sanitiser},
+    template::{Template, Value},
+};
+use clap::{value_parser, Args as ClapArgs};
+use deunicode::deunicode;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use unicode_normalization::UnicodeNormalization;
+
+/// Default `--path-template`, which is also the only layout releases were saved
+/// in before they were recorded.
+const DEFAULT_PATH_TEMPLATE: &str = "{artist}/{title} ({year})";
+
+/// Placeholders available in `--path-template`.
+const PATH_PLACEHOLDERS: &[&str] = &[
+    "artist", "title", "year", "date", "type", "id", "format", "label",
+];
+
+/// Placeholders available in `--track-template`.
+const TRACK_PLACEHOLDERS: &[&str] = &["track", "title", "artist", "album", "ext"];
+
+fn parse_path_template(s: &str) -> Result<Template, String> {
+    Template::parse(s, PATH_PLACEHOLDERS)
+}
+
+fn parse_track_template(s: &str) -> Result<Template, String> {
+    if s.contains('/') {
+        return Err(format!("track template `{s}` cannot contain `/`"));
+    }
+
+    Template::parse(s, TRACK_PLACEHOLDERS)
+}
+
+/// Options controlling where releases are saved to.
+#[derive(Clone, Debug, ClapArgs)]
+pub struct Layout {
+    /// Template for the folder each release is saved in, relative to the
+    /// output folder. Available placeholders are `{artist}`, `{title}`,
+    /// `{year}`, `{date}`, `{type}`, `{id}`, `{format}`, and `{label}` (which
+    /// falls back to the artist for self-released items).
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        default_value = DEFAULT_PATH_TEMPLATE,
+        value_parser = parse_path_template,
+        env = "BS_PATH_TEMPLATE"
+    )]
+    pub path_template: Template,
+
+    /// Template for a folder that single-track releases are saved in together,
+    /// instead of each getting their own folder, e.g. `{artist}/Singles`.
+    /// Takes the same placeholders as `--path-template`.
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        value_parser = parse_path_template,
+        env = "BS_SINGLES_TEMPLATE"
+    )]
+    pub singles_template: Option<Template>,
+
+    /// Template to rename extracted tracks with, e.g. `{track:02} {title}.{ext}`.
+    /// Available placeholders are `{track}`, `{title}`, `{artist}`, `{album}`,
+    /// and `{ext}`. Tracks keep Bandcamp's naming if not set.
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        value_parser = parse_track_template,
+        env = "BS_TRACK_TEMPLATE"
+    )]
+    pub track_template: Option<Template>,
+
+    /// Which filesystem to make file and folder names safe for.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "PROFILE",
+        default_value_t,
+        env = "BS_FS_PROFILE"
+    )]
+    pub fs_profile: FsProfile,
+
+    /// Override how a character is replaced in file and folder names, as
+    /// `FROM=TO`, e.g. `--replace ':=-'`. Can be given multiple times.
+    #[arg(long = "replace", value_name = "FROM=TO")]
+    pub replacements: Vec<Replacement>,
+
+    /// Transliterate file and folder names into plain ASCII, for devices that
+    /// can't display anything else. A `original-names.txt` is saved with each
+    /// release mapping the new names back to the original ones.
+    #[arg(long, env = "BS_ASCII")]
+    pub ascii: bool,
+
+    /// Unicode normalisation form to use for file and folder names. Existing
+    /// folders are matched against new ones in this form, so the same artist
+    /// doesn't end up in two visually identical folders.
+    #[arg(
+        long = "normalize",
+        value_enum,
+        value_name = "FORM",
+        default_value_t,
+        env = "BS_NORMALIZE"
+    )]
+    pub normalisation: Normalisation,
+
+    /// Maximum length of each file and folder name in bytes. Longer names are
+    /// shortened, keeping the year suffix or file extension and adding a short
+    /// hash so they stay unique.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 255,
+        value_parser = value_parser!(u16).range(32..),
+        env = "BS_MAX_NAME_BYTES"
+    )]
+    pub max_name_bytes: u16,
+}
+
+/// Sidecar mapping transliterated names back to their originals, for `--ascii`.
+pub const ORIGINAL_NAMES_FILE: &str = "original-names.txt";
+
+/// Owner of folders shared between single-track releases in `PathClaims`. Real
+/// IDs always start with their item type, so this can't clash with them.
+const SHARED_OWNER: &str = "singles";
+
+/// Information about a release outside of its `DigitalItem` that can be used
+/// in its path.
+pub struct PathContext<'a> {
+    /// ID of the release in the user's collection, e.g. `a1234567`.
+    pub id: &'a str,
+    pub label: Option<&'a str>,
+    pub format: &'a str,
+}
+
+impl Layout {
+    /// The layout versions of Bandsnatch from before releases were recorded
+    /// saved releases in, to find releases they downloaded.
+    pub fn legacy() -> Self {
+        Self {
+            path_template: parse_path_template(DEFAULT_PATH_TEMPLATE)
+                .expect("the default template is valid"),
+            singles_template: None,
+            track_template: None,
+            fs_profile: FsProfile::default(),
+            replacements: vec![],
+            ascii: false,
+            normalisation: Normalisation::default(),
+            max_name_bytes: 255,
+        }
+    }
+
+    pub fn sanitiser(&self) -> Sanitiser {
+        Sanitiser::new(self.fs_profile, &self.replacements, self.ascii)
+            .with_normalisation(self.normalisation)
+    }
+
+    /// Whether a release is saved in a folder shared with other releases,
+    /// rather than its own.
+    pub fn is_shared(&self, item: &DigitalItem) -> bool {
+        self.singles_template.is_some() && item.is_single()
+    }
+
+    /// Template used for a release's folder.
+    fn release_template(&self, item: &DigitalItem) -> &Template {
+        match &self.singles_template {
+            Some(template) if item.is_single() => template,
+            _ => &self.path_template,
+        }
+    }
+
+    /// Folder above a release's folder that's named after just its artist, if
+    /// the template has one.
+    pub fn artist_folder<'a>(&self, release_dir: &'a Path, item: &DigitalItem) -> Option<&'a Path> {
+        let template = self.release_template(item);
+        let index = template.placeholder_component("artist")?;
+        let depth = template.len().checked_sub(index + 1).filter(|d| *d > 0)?;
+
+        release_dir.ancestors().nth(depth)
+    }
+
+    /// Render each component of a release's folder, relative to the output
+    /// folder.
+    fn release_components(
+        &self,
+        item: &DigitalItem,
+        ctx: &PathContext,
+        sanitiser: &Sanitiser,
+    ) -> Vec<String> {
+        let template = self.release_template(item);
+        let components = template.render(
+            |name| match name {
+                "artist" => Value::Text(item.artist.clone()),
+                "title" => Value::Text(item.title.clone()),
+                "year" => Value::Text(item.release_year()),
+                "date" => Value::Text(item.release_date()),
+                "type" => Value::Text(item.item_type.clone()),
+                "id" => Value::Text(ctx.id.to_string()),
+                "format" => Value::Text(ctx.format.to_string()),
+                "label" => Value::Text(ctx.label.unwrap_or(&item.artist).to_string()),
+                _ => unreachable!("placeholders are validated when parsing the template"),
+            },
+            // Values are normalised before they're sanitised, as compatibility
+            // forms would turn replacements like `／` back into `/`.
+            |value| sanitiser.sanitise(value),
+            |text| {
+                if self.ascii {
+                    deunicode(text).replace('/', "-")
+                } else {
+                    self.normalisation.apply(text)
+                }
+            },
+        );
+
+        components
+            .into_iter()
+            .map(|component| {
+                let component = if component.is_empty() {
+                    String::from("_")
+                } else {
+                    component
+                };
+
+                truncate_name(&component, self.max_name_bytes.into())
+            })
+            .collect()
+    }
+
+    /// Build the folder a release should be saved in.
+    pub fn release_path(&self, root: &Path, item: &DigitalItem, ctx: &PathContext) -> PathBuf {
+        self.release_components(item, ctx, &self.sanitiser())
+            .into_iter()
+            .fold(root.to_path_buf(), |path, component| {
+                let existing = self.find_existing(&path, &component);
+                path.join(existing.unwrap_or(component))
+            })
+    }
+
+    /// Find an existing entry in `parent` that has the same name as `name`
+    /// once normalised, if `name` itself doesn't exist.
+    fn find_existing(&self, parent: &Path, name: &str) -> Option<String> {
+        if self.normalisation == Normalisation::None || parent.join(name).exists() {
+            return None;
+        }
+
+        // Names are compared after normalising and then sanitising them, the
+        // same way they're generated, so compatibility forms can't undo any
+        // replacements. `--ascii` is left out so it doesn't match folders with
+        // names it would transliterate.
+        let sanitiser = Sanitiser::new(self.fs_profile, &self.replacements, false)
+            .with_normalisation(self.normalisation);
+        let wanted = sanitiser.sanitise(name);
+        fs::read_dir(parent)
+            .ok()?
+            .filter_map(Result::ok)
+            .find_map(|entry| {
+                let existing = entry.file_name().into_string().ok()?;
+                (sanitiser.sanitise(&existing) == wanted).then_some(existing)
+            })
+    }
+
+    /// Add a disambiguating suffix like ` [album]` to the last component of a
+    /// release's folder.
+    fn disambiguate(&self, path: &Path, suffix: &str) -> PathBuf {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let suffix = format!(" [{}]", self.sanitiser().sanitise(suffix));
+        let max = usize::from(self.max_name_bytes).saturating_sub(suffix.len());
+
+        path.with_file_name(format!("{}{suffix}", truncate_name(&name, max)))
+    }
+
+    /// Pairs of transliterated and original names for each component of a
+    /// release's folder that was changed by `--ascii`.
+    pub fn original_release_names(
+        &self,
+        item: &DigitalItem,
+        ctx: &PathContext,
+    ) -> Vec<(String, String)> {
+        if !self.ascii {
+            return vec![];
+        }
+
+        let original = Sanitiser::new(self.fs_profile, &self.replacements, false)
+            .with_normalisation(self.normalisation);
+        self.release_components(item, ctx, &self.sanitiser())
+            .into_iter()
+            .zip(self.release_components(item, ctx, &original))
+            .filter(|(ascii, original)| ascii != original)
+            .collect()
+    }
+
+    /// Rename any files with non-ASCII names for `--ascii`, returning the
+    /// new paths of all the given files.
+    pub fn transliterate_files(&self, files: Vec<PathBuf>) -> Vec<PathBuf> {
+        if !self.ascii {
+            return files;
+        }
+
+        let sanitiser = self.sanitiser();
+        files
+            .into_iter()
+            .map(|file| {
+                let Some(name) = file.file_name().and_then(|n| n.to_str()) else {
+                    return file;
+                };
+                let renamed = file.with_file_name(truncate_name(
+                    &sanitiser.sanitise(name),
+                    self.max_name_bytes.into(),
+                ));
+
+                if renamed == file || renamed.exists() {
+                    return file;
+                }
+                match fs::rename(&file, &renamed) {
+                    Ok(()) => renamed,
+                    Err(e) => {
+                        warn!("Failed to rename `{}`: {e}", file.display());
+                        file
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Build the filename for a track using `--track-template`, if set.
+    pub fn track_filename(&self, item: &DigitalItem, track: &Track) -> Option<String> {
+        let template = self.track_template.as_ref()?;
+        let sanitiser = self.sanitiser();
+        let components = template.render(
+            |name| match name {
+                "track" => Value::Number(track.number),
+                "title" => Value::Text(track.title.clone()),
+                "artist" => Value::Text(item.artist.clone()),
+                "album" => Value::Text(item.title.clone()),
+                "ext" => Value::Text(
+                    track
+                        .file
+                        .extension()
+                        .map(|e| e.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                ),
+                _ => unreachable!("placeholders are validated when parsing the template"),
+            },
+            |value| sanitiser.sanitise(value),
+            str::to_string,
+        );
+
+        components
+            .into_iter()
+            .next()
+            .filter(|name| !name.is_empty())
+            .map(|name| truncate_name(&name, self.max_name_bytes.into()))
+    }
+}
+
+/// Write `original-names.txt` into a release folder, mapping each transliterated
+/// name to its original. Names already in the file are kept, as the folder may
+/// be shared with other releases.
+pub fn write_original_names(dir: &Path, names: &[(String, String)]) -> Result<(), Box<dyn Error>> {
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    let path = dir.join(ORIGINAL_NAMES_FILE);
+    let mut existing = fs::read_to_string(&path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(ascii, original)| (ascii.to_string(), original.to_string()))
+        .filter(|(ascii, _)| !names.iter().any(|(name, _)| name == ascii))
+        .collect::<Vec<_>>();
+    existing.extend_from_slice(names);
+
+    let mut file = File::create(path)?;
+    for (ascii, original) in existing {
+        writeln!(file, "{ascii}\t{original}")?;
+    }
+
+    Ok(())
+}
+
+/// Key used to compare paths. Paths are compared case-insensitively and
+/// normalised, as that's how some filesystems will treat them.
+pub fn path_key(path: &Path) -> String {
+    path.to_string_lossy()
+        .nfc()
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Keeps track of which release owns each folder, so that two different
+/// releases that map to the same folder aren't extracted over each other.
+pub struct PathClaims {
+    root: PathBuf,
+    claims: Mutex<HashMap<String, String>>,
+    /// Whether folders already on disk are taken, even if they aren't claimed.
+    check_disk: bool,
+}
+
+/// Whether `dir` has files Bandcamp named after `item` in it, like its download
+/// or the tracks extracted from it. These are left behind by downloads of the
+/// release that were interrupted or failed to extract, and by downloads from
+/// before releases were recorded.
+fn has_own_files(layout: &Layout, dir: &Path, item: &DigitalItem) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return false;
+    };
+    let max = usize::from(layout.max_name_bytes);
+    let name = layout
+        .sanitiser()
+        .sanitise(&format!("{} - {}", item.artist, item.title));
+    let download = truncate_name(&format!("{name}.zip"), max);
+    let track_prefix = format!("{name} - ");
+
+    entries.filter_map(Result::ok).any(|entry| {
+        let file = PathBuf::from(entry.file_name());
+        let Some(stem) = file.file_stem().and_then(|s| s.to_str()) else {
+            return false;
+        };
+
+        if item.is_single() {
+            extract::is_audio(&file) && stem == name
+        } else {
+            file.as_os_str() == download.as_str()
+                || (extract::is_audio(&file)
+                    && stem
+                        .strip_prefix(&track_prefix)
+                        .is_some_and(|rest| extract::split_track_number(rest).is_some()))
+        }
+    })
+}
+
+impl PathClaims {
+    pub fn new(root: &Path, releases: &HashMap<String, Release>) -> Self {
+        let claims = Self {
+            root: root.to_path_buf(),
+            claims: Mutex::new(HashMap::new()),
+            check_disk: true,
+        };
+
+        {
+            let mut map = claims.claims.lock().unwrap();
+            for release in releases.values() {
+                let owner = if release.shared {
+                    SHARED_OWNER
+                } else {
+                    &release.id
+                };
+                map.insert(claims.key(&root.join(&release.path)), owner.to_string());
+            }
+        }
+
+        claims
+    }
+
+    /// Claims for moving every release to a new folder, where folders on disk
+    /// are only taken once they're claimed, as releases may be moving out of
+    /// them.
+    pub fn for_moves(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            claims: Mutex::new(HashMap::new()),
+            check_disk: false,
+        }
+    }
+
+    fn key(&self, path: &Path) -> String {
+        path_key(path.strip_prefix(&self.root).unwrap_or(path))
+    }
+
+    /// Whether a folder belongs to a release other than `id`, either by being
+    /// claimed by it, or by having anything in it without being claimed at
+    /// all. Empty folders are fair game, as failed downloads leave them behind,
+    /// as are folders with the release's own files in them.
+    fn is_taken(
+        &self,
+        claims: &HashMap<String, String>,
+        layout: &Layout,
+        path: &Path,
+        id: &str,
+        item: &DigitalItem,
+    ) -> bool {
+        match claims.get(&self.key(path)) {
+            Some(owner) => owner != id,
+            None => {
+                self.check_disk
+                    && id != SHARED_OWNER
+                    && fs::read_dir(path).is_ok_and(|mut entries| entries.next().is_some())
+                    && !has_own_files(layout, path, item)
+            }
+        }
+    }
+
+    /// Whether a folder is claimed by a release other than `id`.
+    pub fn is_claimed_by_other(&self, path: &Path, id: &str) -> bool {
+        self.claims
+            .lock()
+            .unwrap()
+            .get(&self.key(path))
+            .is_some_and(|owner| owner != id)
+    }
+
+    /// The first folder a release could claim, trying disambiguated folders if
+    /// it already belongs to a different release.
+    fn find_free(
+        &self,
+        claims: &HashMap<String, String>,
+        layout: &Layout,
+        id: &str,
+        item: &DigitalItem,
+        path: PathBuf,
+    ) -> PathBuf {
+        let candidates = [
+            path.clone(),
+            layout.disambiguate(&path, &item.item_type),
+            layout.disambiguate(&path, id),
+        ];
+
+        // Numbered folders are a last resort, for when even the folder named
+        // after the ID is taken by something else.
+        let numbered = (2..).map(|n: u32| layout.disambiguate(&path, &n.to_string()));
+        candidates
+            .into_iter()
+            .chain(numbered)
+            .find(|candidate| {
+                let taken = self.is_taken(claims, layout, candidate, id, item);
+                if taken {
+                    debug!(
+                        "`{}` already belongs to another release, trying another folder for {id}",
+                        candidate.display()
+                    );
+                }
+                !taken
+            })
+            .expect("there are always more numbered folders to try")
+    }
+
+    /// The folder [`PathClaims::claim`] would give a release, without claiming
+    /// it.
+    pub fn lookup(&self, layout: &Layout, id: &str, item: &DigitalItem, path: PathBuf) -> PathBuf {
+        let id = if layout.is_shared(item) {
+            SHARED_OWNER
+        } else {
+            id
+        };
+        let claims = self.claims.lock().unwrap();
+        self.find_free(&claims, layout, id, item, path)
+    }
+
+    /// Claim a folder for a release, returning a disambiguated folder if it
+    /// already belongs to a different release. Folders shared between singles
+    /// can be claimed by any of them.
+    pub fn claim(&self, layout: &Layout, id: &str, item: &DigitalItem, path: PathBuf) -> PathBuf {
+        let id = if layout.is_shared(item) {
+            SHARED_OWNER
+        } else {
+            id
+        };
+        let mut claims = self.claims.lock().unwrap();
+        let claimed = self.find_free(&claims, layout, id, item, path);
+
+        claims.insert(self.key(&claimed), id.to_string());
+        claimed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{item, layout, single, temp_dir, Args};
+    use clap::Parser;
+
+    fn track(number: u64, title: &str) -> Track {
+        Track {
+            number,
+            title: title.to_string(),
+            file: PathBuf::from("Artist - Album - 01 Song.flac"),
+        }
+    }
+
+    fn components(layout: &Layout, item: &DigitalItem) -> Vec<String> {
+        let ctx = PathContext {
+            id: "a1",
+            label: None,
+            format: "flac",
+        };
+        layout.release_components(item, &ctx, &layout.sanitiser())
+    }
+
+    #[test]
+    fn release_names_stay_sanitised_in_every_form() {
+        let item = item("AC/DC", "What? Now／Then？");
+        for form in ["none", "nfc", "nfd", "nfkc", "nfkd"] {
+            let layout = layout(&["--normalize", form]);
+            let components = components(&layout, &item);
+            assert_eq!(components.len(), 2, "{form}: {components:?}");
+            for component in &components {
+                assert!(
+                    !component.contains(['/', '?']),
+                    "{form}: `{component}` isn't sanitised"
+                );
+            }
+
+            let posix = self::layout(&["--normalize", form, "--fs-profile", "posix"]);
+            let components = self::components(&posix, &item);
+            assert!(
+                components.iter().all(|c| !c.contains('/')),
+                "{form}: {components:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn release_names_are_normalised() {
+        let item = item("Cafe\u{301}", "ﬁ");
+        let nfc = components(&layout(&["--normalize", "nfc"]), &item);
+        assert_eq!(nfc, ["Caf\u{e9}", "ﬁ (2021)"]);
+        let nfkd = components(&layout(&["--normalize", "nfkd"]), &item);
+        assert_eq!(nfkd, ["Cafe\u{301}", "fi (2021)"]);
+        let ascii = components(
+            &layout(&["--ascii", "--path-template", "{artist}/Ä {title}"]),
+            &item,
+        );
+        assert_eq!(ascii, ["Cafe", "A fi"]);
+    }
+
+    #[test]
+    fn finds_existing_folders_in_other_forms() {
+        let root = temp_dir("find-existing");
+        fs::create_dir(root.join("Cafe\u{301}")).unwrap();
+        fs::create_dir(root.join("What？")).unwrap();
+
+        let nfc = layout(&["--normalize", "nfc"]);
+        assert_eq!(
+            nfc.find_existing(&root, "Caf\u{e9}").as_deref(),
+            Some("Cafe\u{301}")
+        );
+        assert_eq!(nfc.find_existing(&root, "What？"), None);
+
+        let nfkc = layout(&["--normalize", "nfkc"]);
+        assert_eq!(
+            nfkc.find_existing(&root, "Caf\u{e9}").as_deref(),
+            Some("Cafe\u{301}")
+        );
+        assert_eq!(nfkc.find_existing(&root, "What_"), None);
+
+        let ascii = layout(&["--ascii"]);
+        assert_eq!(ascii.find_existing(&root, "Cafe"), None);
+
+        let none = layout(&["--normalize", "none"]);
+        assert_eq!(none.find_existing(&root, "Caf\u{e9}"), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn file_in(dir: &Path, name: &str) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(name), "").unwrap();
+    }
+
+    fn folder_name(path: &Path) -> String {
+        path.file_name().unwrap().to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn claims_disambiguate_between_releases() {
+        let root = temp_dir("claims");
+        let layout = layout(&[]);
+        let claims = PathClaims::new(&root, &HashMap::new());
+        let item = item("Artist", "Album");
+        let path = root.join("Artist").join("Album (2021)");
+
+        let claim = |id| folder_name(&claims.claim(&layout, id, &item, path.clone()));
+        let lookup = |id| folder_name(&claims.lookup(&layout, id, &item, path.clone()));
+        assert_eq!(claim("a1"), "Album (2021)");
+        // Looking a folder up leaves it free for the next claim.
+        assert_eq!(lookup("a2"), "Album (2021) [album]");
+        assert_eq!(lookup("a3"), "Album (2021) [album]");
+        assert_eq!(claim("a2"), "Album (2021) [album]");
+        assert_eq!(claim("a3"), "Album (2021) [a3]");
+        assert_eq!(claim("a1"), "Album (2021)");
+        assert_eq!(claim("a2"), "Album (2021) [album]");
+
+        assert!(claims.is_claimed_by_other(&path, "a2"));
+        assert!(!claims.is_claimed_by_other(&path, "a1"));
+        assert!(!claims.is_claimed_by_other(&root.join("Other"), "a1"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn claims_avoid_unclaimed_folders_on_disk() {
+        let root = temp_dir("claims-disk");
+        let layout = layout(&[]);
+        let item = item("Artist", "Album");
+        let path = root.join("Artist").join("Album (2021)");
+
+        // Empty folders are left behind by failed downloads, and can be used.
+        fs::create_dir_all(&path).unwrap();
+        let claims = PathClaims::new(&root, &HashMap::new());
+        assert_eq!(claims.claim(&layout, "a1", &item, path.clone()), path);
+
+        file_in(&path, "song.flac");
+        let claims = PathClaims::new(&root, &HashMap::new());
+        assert_eq!(
+            folder_name(&claims.claim(&layout, "a1", &item, path.clone())),
+            "Album (2021) [album]"
+        );
+
+        // Moves take folders that releases are moving out of.
+        let claims = PathClaims::for_moves(&root);
+        assert_eq!(claims.claim(&layout, "a1", &item, path.clone()), path);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn claims_take_folders_with_the_releases_own_files() {
+        let root = temp_dir("claims-own");
+        let layout = layout(&[]);
+        let item = item("Artist", "Album");
+        let path = root.join("Artist").join("Album (2021)");
+        let claim = |item: &DigitalItem| {
+            let claims = PathClaims::new(&root, &HashMap::new());
+            folder_name(&claims.claim(&layout, "a1", item, path.clone()))
+        };
+
+        // An interrupted download leaves part of the archive behind.
+        file_in(&path, "Artist - Album.zip");
+        assert_eq!(claim(&item), "Album (2021)");
+
+        // A failed extract leaves the archive and some of its tracks.
+        file_in(&path, "Artist - Album - 01 Song.flac");
+        assert_eq!(claim(&item), "Album (2021)");
+
+        // Releases downloaded before they were recorded only have tracks.
+        fs::remove_file(path.join("Artist - Album.zip")).unwrap();
+        file_in(&path, "cover.jpg");
+        assert_eq!(claim(&item), "Album (2021)");
+
+        // A single with the same name is a different release.
+        assert_eq!(claim(&single("Artist", "Album")), "Album (2021) [track]");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn claims_fall_back_to_numbered_folders() {
+        let root = temp_dir("claims-numbered");
+        let layout = layout(&[]);
+        let item = item("Artist", "Album");
+        let path = root.join("Artist").join("Album (2021)");
+        file_in(&root.join("Artist").join("Album (2021) [a3]"), "song.flac");
+        file_in(&root.join("Artist").join("Album (2021) [2]"), "song.flac");
+
+        let claims = PathClaims::new(&root, &HashMap::new());
+        let claim = |id| folder_name(&claims.claim(&layout, id, &item, path.clone()));
+        assert_eq!(claim("a1"), "Album (2021)");
+        assert_eq!(claim("a2"), "Album (2021) [album]");
+        assert_eq!(claim("a3"), "Album (2021) [3]");
+        assert_eq!(claim("a4"), "Album (2021) [a4]");
+        assert_eq!(claim("a3"), "Album (2021) [3]");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn legacy_layout_matches_the_default() {
+        let item = item("Artist", "Album: Part 1");
+        let ctx = PathContext {
+            id: "a1",
+            label: None,
+            format: "flac",
+        };
+        let root = Path::new("/music");
+        assert_eq!(
+            Layout::legacy().release_path(root, &item, &ctx),
+            layout(&[]).release_path(root, &item, &ctx)
+        );
+    }
+
+    #[test]
+    fn track_filename_needs_a_template() {
+        let item = item("Artist", "Album");
+        assert_eq!(layout(&[]).track_filename(&item, &track(1, "Song")), None);
+    }
+
+    #[test]
+    fn track_filename_fills_placeholders() {
+        let layout = layout(&[
+            "--track-template",
+            "{track:02} {artist} - {album} - {title}.{ext}",
+        ]);
+        let item = item("Artist", "Album");
+        assert_eq!(
+            layout.track_filename(&item, &track(3, "Song")).unwrap(),
+            "03 Artist - Album - Song.flac"
+        );
+        assert_eq!(
+            layout.track_filename(&item, &track(120, "Song")).unwrap(),
+            "120 Artist - Album - Song.flac"
+        );
+    }
+
+    #[test]
+    fn track_filename_sanitises_values() {
+        let layout = layout(&["--track-template", "{track} {title}.{ext}"]);
+        let item = item("Artist", "Album");
+        let name = layout
+            .track_filename(&item, &track(1, "What? / Why: <Yes>"))
+            .unwrap();
+        assert!(!name.contains(['/', '?', ':', '<', '>']), "{name}");
+        assert!(name.starts_with("1 ") && name.ends_with(".flac"), "{name}");
+    }
+
+    #[test]
+    fn track_filename_is_truncated() {
+        let layout = layout(&[
+            "--track-template",
+            "{track:02} {title}.{ext}",
+            "--max-name-bytes",
+            "40",
+        ]);
+        let name = layout
+            .track_filename(&item("Artist", "Album"), &track(1, &"é".repeat(100)))
+            .unwrap();
+        assert!(name.len() <= 40, "{name}");
+        assert!(name.starts_with("01 ") && name.ends_with(".flac"), "{name}");
+    }
+
+    #[test]
+    fn track_template_cannot_make_folders() {
+        assert!(
+            Args::try_parse_from(["bandsnatch", "--track-template", "{artist}/{title}"]).is_err()
+        );
+    }
+}
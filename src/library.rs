@@ -0,0 +1,530 @@
+use crate::{
+    api::{
+        structs::{DownloadsMap, ItemsMap},
+        Api,
+    },
+    cache::Release,
+    layout::{Layout, PathClaims, PathContext},
+    nfo,
+};
+use indicatif::MultiProgress;
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Journal of moves made while reorganising the library, so that they can be
+/// rolled back.
+const JOURNAL_FILE: &str = "bandsnatch-reorganize.journal";
+
+/// A single release being moved, with every path relative to the output folder.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub id: String,
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// Individual moves made, which is just `from -> to` unless the release was
+    /// merged into an existing folder.
+    pub moves: Vec<(PathBuf, PathBuf)>,
+    /// Whether the release was in a shared folder before being moved.
+    #[serde(default)]
+    pub shared: bool,
+}
+
+/// Moves needed to get a release from one folder into another.
+pub struct MovePlan {
+    pub moves: Vec<(PathBuf, PathBuf)>,
+    /// Entries that already exist in the destination, and are left in place.
+    pub conflicts: Vec<PathBuf>,
+}
+
+/// Whether two paths are the same file or folder on disk, like names that only
+/// differ in case on a case-insensitive filesystem.
+pub fn is_same_file(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        matches!(
+            (fs::metadata(a), fs::metadata(b)),
+            (Ok(a), Ok(b)) if a.dev() == b.dev() && a.ino() == b.ino()
+        )
+    }
+    #[cfg(not(unix))]
+    {
+        matches!(
+            (fs::canonicalize(a), fs::canonicalize(b)),
+            (Ok(a), Ok(b)) if a == b
+        )
+    }
+}
+
+/// Work out how to move a release's folder from `from` to `to`, both relative
+/// to `root`. If `to` already exists, the contents of `from` are merged into it
+/// instead.
+pub fn plan_move(root: &Path, from: &Path, to: &Path) -> Result<MovePlan, Box<dyn Error>> {
+    if !root.join(to).exists() {
+        return Ok(MovePlan {
+            moves: vec![(from.to_path_buf(), to.to_path_buf())],
+            conflicts: vec![],
+        });
+    }
+
+    // `to` is the release's own folder, with only the case or normalisation
+    // changing, so go through a temporary name instead. On case-sensitive
+    // filesystems a folder with a name like this is a different one, and is
+    // merged into like any other.
+    if is_same_file(&root.join(from), &root.join(to)) {
+        let mut temporary = to.as_os_str().to_owned();
+        temporary.push(".bandsnatch-tmp");
+        let temporary = PathBuf::from(temporary);
+
+        return Ok(MovePlan {
+            moves: vec![
+                (from.to_path_buf(), temporary.clone()),
+                (temporary, to.to_path_buf()),
+            ],
+            conflicts: vec![],
+        });
+    }
+
+    let mut moves = vec![];
+    let mut conflicts = vec![];
+    for entry in fs::read_dir(root.join(from))? {
+        let name = entry?.file_name();
+        if root.join(to).join(&name).exists() {
+            conflicts.push(from.join(&name));
+        } else {
+            moves.push((from.join(&name), to.join(&name)));
+        }
+    }
+
+    Ok(MovePlan { moves, conflicts })
+}
+
+/// Work out how to move only the given files of a release from `from` to `to`,
+/// for releases that share their folder with others.
+pub fn plan_file_moves(root: &Path, from: &Path, to: &Path, files: &[PathBuf]) -> MovePlan {
+    let mut moves = vec![];
+    let mut conflicts = vec![];
+    for file in files {
+        let (source, target) = (root.join(from).join(file), root.join(to).join(file));
+        // Files are already in place when only the case of the folder changes
+        // on a case-insensitive filesystem.
+        if !source.exists() || is_same_file(&source, &target) {
+            continue;
+        }
+
+        if target.exists() {
+            conflicts.push(from.join(file));
+        } else {
+            moves.push((from.join(file), to.join(file)));
+        }
+    }
+
+    MovePlan { moves, conflicts }
+}
+
+/// Work out how to move a release to `to`, only moving its own files if it's
+/// moving into or out of a shared folder.
+pub fn plan_release_move(
+    root: &Path,
+    release: &Release,
+    to: &Path,
+    shared: bool,
+) -> Result<MovePlan, Box<dyn Error>> {
+    if shared || release.shared {
+        Ok(plan_file_moves(root, &release.path, to, &release.files))
+    } else {
+        plan_move(root, &release.path, to)
+    }
+}
+
+/// Perform moves relative to `root`, creating any missing folders.
+pub fn apply_moves(root: &Path, moves: &[(PathBuf, PathBuf)]) -> Result<(), Box<dyn Error>> {
+    for (from, to) in moves {
+        let to = root.join(to);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(root.join(from), to)?;
+    }
+
+    Ok(())
+}
+
+/// Undo moves made by [`apply_moves`], last first. Each move is checked as it's
+/// undone, as earlier ones may depend on later ones, like renames through a
+/// temporary name. Moves that never happened, from a failure partway through,
+/// are skipped.
+pub fn undo_moves(root: &Path, moves: &[(PathBuf, PathBuf)]) -> Result<(), Box<dyn Error>> {
+    for (from, to) in moves.iter().rev() {
+        let (source, target) = (root.join(to), root.join(from));
+        // The target is only in the way if it's a different file, and not just
+        // a name differing in case on a case-insensitive filesystem.
+        if !source.exists() || (target.exists() && !is_same_file(&source, &target)) {
+            continue;
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(source, target)?;
+    }
+
+    Ok(())
+}
+
+/// Move an `artist.nfo` left on its own in a folder above `from`, after a
+/// release moved out of it, into `artist_dir` so the old folder can be removed.
+/// It's removed instead if there's no new artist folder, or it already has one.
+pub fn move_artist_nfo(
+    root: &Path,
+    from: &Path,
+    artist_dir: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let from = root.join(from);
+    for dir in from.ancestors().skip(1) {
+        if dir == root || !dir.starts_with(root) {
+            break;
+        }
+
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        let names = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.file_name())
+            .collect::<Vec<_>>();
+        if names != [nfo::ARTIST_NFO] {
+            continue;
+        }
+
+        let nfo = dir.join(nfo::ARTIST_NFO);
+        match artist_dir {
+            Some(new) if !new.join(nfo::ARTIST_NFO).exists() => {
+                fs::create_dir_all(new)?;
+                fs::rename(nfo, new.join(nfo::ARTIST_NFO))?;
+            }
+            _ => fs::remove_file(nfo)?,
+        }
+        break;
+    }
+
+    Ok(())
+}
+
+/// Remove `dir` and any of its parents below `root` that are left empty.
+pub fn remove_empty_dirs(root: &Path, dir: &Path) {
+    let mut dir = root.join(dir);
+    while dir.starts_with(root) && dir != root {
+        // Fails if the folder isn't empty, which is where we want to stop.
+        if dir.exists() && fs::remove_dir(&dir).is_err() {
+            break;
+        }
+        if !dir.pop() {
+            break;
+        }
+    }
+}
+
+/// What's needed to find releases downloaded by a version of Bandsnatch that
+/// didn't record where they were saved.
+pub struct Unrecorded<'a> {
+    pub root: &'a Path,
+    /// Layout the releases were downloaded with.
+    pub layout: &'a Layout,
+    /// Audio format the releases were downloaded in.
+    pub audio_format: &'a str,
+    pub download_urls: &'a DownloadsMap,
+    pub collection_items: &'a ItemsMap,
+    /// Folders belonging to recorded releases, which unrecorded ones can't be
+    /// in.
+    pub claims: &'a PathClaims,
+    pub debug: bool,
+}
+
+impl Unrecorded<'_> {
+    /// Work out where a release would have been saved from its digital item,
+    /// returning a record for it if it's there.
+    pub fn find(
+        &self,
+        api: &Api,
+        id: &str,
+        m: &MultiProgress,
+    ) -> Result<Option<Release>, Box<dyn Error>> {
+        let Some(url) = self.download_urls.get(id) else {
+            m.suspend(|| debug!("{id} isn't in the collection anymore, skipping"));
+            return Ok(None);
+        };
+        let Some(item) = api.get_digital_item(url, &self.debug)? else {
+            return Ok(None);
+        };
+
+        if self.layout.is_shared(&item) {
+            m.suspend(|| {
+                warn!("{id} is in a folder shared with other singles and doesn't have a record of its files, skipping")
+            });
+            return Ok(None);
+        }
+
+        let label = self.collection_items.get(id).and_then(|i| i.label.clone());
+        let path = item.destination_path(
+            self.root,
+            self.layout,
+            &PathContext {
+                id,
+                label: label.as_deref(),
+                format: self.audio_format,
+            },
+        );
+        // Releases only got disambiguated folders once they started being
+        // recorded, so this is where an unrecorded one would be, unless another
+        // release has since been recorded there.
+        let path = PathBuf::from(path);
+        if self.claims.is_claimed_by_other(&path, id) {
+            m.suspend(|| {
+                debug!(
+                    "`{}` belongs to another release, so {id} isn't on disk",
+                    path.display()
+                )
+            });
+            return Ok(None);
+        }
+        if !path.is_dir() {
+            m.suspend(|| debug!("`{}` ({id}) isn't on disk, skipping", path.display()));
+            return Ok(None);
+        }
+
+        // Releases are extracted flat, apart from any extras.
+        let files = fs::read_dir(&path)?
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+            .map(|entry| PathBuf::from(entry.file_name()))
+            .filter(|name| !name.to_string_lossy().starts_with('.'))
+            .collect();
+
+        Ok(Some(Release {
+            id: id.to_string(),
+            path: path.strip_prefix(self.root).unwrap_or(&path).to_path_buf(),
+            files,
+            tracks: vec![],
+            item,
+            label,
+            format: self.audio_format.to_string(),
+            shared: false,
+            collection_artist: None,
+            collection_title: None,
+            purchased: None,
+        }))
+    }
+}
+
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            path: root.join(JOURNAL_FILE),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Record a move before it is made, flushing it to disk so it survives
+    /// a crash partway through.
+    pub fn record(&self, entry: &JournalEntry) -> Result<(), Box<dyn Error>> {
+        let mut file = File::options().create(true).append(true).open(&self.path)?;
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        file.write_all(line.as_bytes())?;
+        file.sync_all()?;
+
+        Ok(())
+    }
+
+    pub fn entries(&self) -> Result<Vec<JournalEntry>, Box<dyn Error>> {
+        let content = fs::read_to_string(&self.path)?;
+        let mut entries = vec![];
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            entries.push(serde_json::from_str(line)?);
+        }
+
+        Ok(entries)
+    }
+
+    pub fn remove(&self) -> Result<(), Box<dyn Error>> {
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn paths(moves: &[(PathBuf, PathBuf)]) -> Vec<(&str, &str)> {
+        moves
+            .iter()
+            .map(|(from, to)| (from.to_str().unwrap(), to.to_str().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn moves_to_new_folders() {
+        let root = temp_dir("move-new");
+        fs::create_dir_all(root.join("a/album")).unwrap();
+
+        let plan = plan_move(&root, Path::new("a/album"), Path::new("A/Album")).unwrap();
+        assert_eq!(paths(&plan.moves), [("a/album", "A/Album")]);
+        assert!(plan.conflicts.is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn merges_into_other_folders_differing_in_case() {
+        let root = temp_dir("move-case");
+        fs::create_dir_all(root.join("album")).unwrap();
+        fs::write(root.join("album/1.flac"), "").unwrap();
+        fs::write(root.join("album/2.flac"), "").unwrap();
+        fs::create_dir_all(root.join("Album")).unwrap();
+        fs::write(root.join("Album/1.flac"), "").unwrap();
+
+        // Both only exist at once on case-sensitive filesystems, where they're
+        // different folders.
+        if !is_same_file(&root.join("album"), &root.join("Album")) {
+            let plan = plan_move(&root, Path::new("album"), Path::new("Album")).unwrap();
+            assert_eq!(paths(&plan.moves), [("album/2.flac", "Album/2.flac")]);
+            assert_eq!(plan.conflicts, [PathBuf::from("album/1.flac")]);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn renames_the_same_folder_through_a_temporary_name() {
+        let root = temp_dir("move-same");
+        fs::create_dir_all(root.join("album")).unwrap();
+        fs::write(root.join("album/1.flac"), "").unwrap();
+        // Stands in for a case-insensitive filesystem.
+        std::os::unix::fs::symlink("album", root.join("Album")).unwrap();
+
+        let plan = plan_move(&root, Path::new("album"), Path::new("Album")).unwrap();
+        assert_eq!(
+            paths(&plan.moves),
+            [
+                ("album", "Album.bandsnatch-tmp"),
+                ("Album.bandsnatch-tmp", "Album")
+            ]
+        );
+
+        let plan = plan_file_moves(
+            &root,
+            Path::new("album"),
+            Path::new("Album"),
+            &[PathBuf::from("1.flac")],
+        );
+        assert!(plan.moves.is_empty() && plan.conflicts.is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn undoes_renames_through_a_temporary_name() {
+        let root = temp_dir("undo-same");
+        fs::create_dir_all(root.join("real")).unwrap();
+        fs::write(root.join("real/1.flac"), "").unwrap();
+        // Both names are the same folder, like on a case-insensitive
+        // filesystem.
+        std::os::unix::fs::symlink("real", root.join("album")).unwrap();
+        std::os::unix::fs::symlink("real", root.join("Album")).unwrap();
+
+        let moves = [
+            (
+                PathBuf::from("album"),
+                PathBuf::from("Album.bandsnatch-tmp"),
+            ),
+            (
+                PathBuf::from("Album.bandsnatch-tmp"),
+                PathBuf::from("Album"),
+            ),
+        ];
+        undo_moves(&root, &moves).unwrap();
+        assert!(root.join("album/1.flac").exists());
+        assert!(fs::symlink_metadata(root.join("Album.bandsnatch-tmp")).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn undoes_moves_that_depend_on_each_other() {
+        let root = temp_dir("undo-chain");
+        fs::create_dir_all(root.join("B")).unwrap();
+        fs::write(root.join("B/1.flac"), "").unwrap();
+
+        // Only the last move is possible until the one after it is undone.
+        let moves = [
+            (PathBuf::from("a"), PathBuf::from("tmp")),
+            (PathBuf::from("tmp"), PathBuf::from("B")),
+        ];
+        undo_moves(&root, &moves).unwrap();
+        assert!(root.join("a/1.flac").exists());
+        assert!(!root.join("tmp").exists() && !root.join("B").exists());
+
+        // Moves that never happened are skipped.
+        undo_moves(&root, &moves).unwrap();
+        assert!(root.join("a/1.flac").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn moves_artist_nfo_out_of_emptied_folders() {
+        let root = temp_dir("artist-nfo");
+        fs::create_dir_all(root.join("Old/Album")).unwrap();
+        fs::write(root.join("Old/artist.nfo"), "old").unwrap();
+        fs::create_dir_all(root.join("New/Album")).unwrap();
+
+        // Other releases are still using it.
+        fs::create_dir_all(root.join("Old/Other")).unwrap();
+        move_artist_nfo(&root, Path::new("Old/Album"), Some(&root.join("New"))).unwrap();
+        assert!(root.join("Old/artist.nfo").exists());
+
+        fs::remove_dir_all(root.join("Old/Other")).unwrap();
+        fs::remove_dir_all(root.join("Old/Album")).unwrap();
+        move_artist_nfo(&root, Path::new("Old/Album"), Some(&root.join("New"))).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("New/artist.nfo")).unwrap(),
+            "old"
+        );
+        remove_empty_dirs(&root, Path::new("Old/Album"));
+        assert!(!root.join("Old").exists());
+
+        // The new folder's own one is kept.
+        fs::create_dir_all(root.join("Old")).unwrap();
+        fs::write(root.join("Old/artist.nfo"), "older").unwrap();
+        move_artist_nfo(&root, Path::new("Old/Album"), Some(&root.join("New"))).unwrap();
+        assert!(!root.join("Old/artist.nfo").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("New/artist.nfo")).unwrap(),
+            "old"
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -2,6 +2,19 @@ mod api;
 mod cache;
 mod cmds;
 mod cookies;
+mod enrich;
+mod extract;
+mod layout;
+mod library;
+mod nfo;
+mod playlist;
+mod replaygain;
+mod sanitise;
+mod tags;
+mod template;
+#[cfg(test)]
+mod test_util;
+mod throttle;
 mod util;
 
 #[macro_use]
@@ -22,7 +35,11 @@ struct Args {
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Run Bandsnatch to download your collection.
-    Run(cmds::run::Args),
+    Run(Box<cmds::run::Args>),
+    /// Move already downloaded releases to match new layout options.
+    Reorganize(cmds::reorganize::Args),
+    /// Add covers, tags, and other extras to already downloaded releases.
+    Backfill(cmds::backfill::Args),
     DebugCollection(cmds::debug_collection::Args), // Get the raw JSON of a specific Bandcamp release for debugging.
                                                    // Release(cmds::release::Args),
 }
@@ -37,7 +54,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
     match args.command {
-        Commands::Run(cmd_args) => cmds::run::command(cmd_args),
+        Commands::Run(cmd_args) => cmds::run::command(*cmd_args),
+        Commands::Reorganize(cmd_args) => cmds::reorganize::command(cmd_args),
+        Commands::Backfill(cmd_args) => cmds::backfill::command(cmd_args),
         Commands::DebugCollection(cmd_args) => cmds::debug_collection::command(cmd_args),
         // Commands::Release(cmd_args) => cmds::release::command(cmd_args).await,
     }
@@ -0,0 +1,244 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+use url::Url;
+
+use crate::api::structs::{DigitalItem, ReleasePage};
+
+/// Name of the metadata file saved in each release's folder.
+const ALBUM_NFO: &str = "album.nfo";
+/// Name of the metadata file saved in each artist's folder.
+pub const ARTIST_NFO: &str = "artist.nfo";
+
+/// Details about a release to write into its `album.nfo`.
+pub struct AlbumInfo<'a> {
+    pub item: &'a DigitalItem,
+    pub label: Option<&'a str>,
+    pub url: Option<&'a str>,
+    /// Path to the release's cover, relative to its folder.
+    pub cover: Option<&'a str>,
+    pub page: Option<&'a ReleasePage>,
+}
+
+/// Escape text for use in XML.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Control characters aren't allowed in XML at all.
+            c if c.is_control() && !matches!(c, '\n' | '\t') => {}
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// An indented element with some text.
+fn element(depth: usize, name: &str, text: &str) -> String {
+    format!("{}<{name}>{}</{name}>\n", "  ".repeat(depth), escape(text))
+}
+
+/// Write an `.nfo` file with the given root element, leaving it alone if it
+/// hasn't changed.
+fn write_nfo(path: &Path, root: &str, elements: &[String]) -> Result<(), Box<dyn Error>> {
+    let content = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<{root}>\n{}</{root}>\n",
+        elements.concat()
+    );
+    if fs::read_to_string(path).is_ok_and(|existing| existing == content) {
+        return Ok(());
+    }
+
+    fs::write(path, content)?;
+
+    Ok(())
+}
+
+/// Format a track's length as `m:ss`.
+fn duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Write `album.nfo` into a release's folder.
+pub fn write_album_nfo(dir: &Path, info: &AlbumInfo) -> Result<PathBuf, Box<dyn Error>> {
+    let item = info.item;
+    let mut elements = vec![
+        element(1, "title", &item.title),
+        element(1, "artist", &item.artist),
+        element(1, "albumartist", &item.artist),
+    ];
+
+    if let Some(date) = item.known_release_date() {
+        elements.push(element(1, "year", &item.release_year()));
+        elements.push(element(1, "releasedate", &date));
+    }
+    if let Some(label) = info.label {
+        elements.push(element(1, "label", label));
+    }
+    if let Some(page) = info.page {
+        elements.extend(page.tags.iter().map(|tag| element(1, "genre", tag)));
+        if let Some(about) = &page.current.about {
+            elements.push(element(1, "review", about.trim()));
+        }
+    }
+    if let Some(url) = info.url {
+        elements.push(element(1, "website", url));
+    }
+    if let Some(cover) = info.cover {
+        elements.push(format!(
+            "  <thumb aspect=\"front\">{}</thumb>\n",
+            escape(cover)
+        ));
+    }
+
+    if let Some(page) = info.page {
+        for (i, track) in page.trackinfo.iter().enumerate() {
+            let position = track.track_num.unwrap_or(i as u64 + 1);
+            let mut track_elements = element(2, "position", &position.to_string());
+            if let Some(title) = &track.title {
+                track_elements.push_str(&element(2, "title", title));
+            }
+            if let Some(length) = track.duration.filter(|d| *d > 0.0) {
+                track_elements.push_str(&element(2, "duration", &duration(length)));
+            }
+            elements.push(format!("  <track>\n{track_elements}  </track>\n"));
+        }
+    }
+
+    let path = dir.join(ALBUM_NFO);
+    write_nfo(&path, "album", &elements)?;
+
+    Ok(path)
+}
+
+/// Write `artist.nfo` into an artist's folder. Their Bandcamp page is worked
+/// out from the URL of one of their releases.
+pub fn write_artist_nfo(
+    dir: &Path,
+    artist: &str,
+    release_url: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let mut elements = vec![element(1, "name", artist)];
+    let website = release_url
+        .and_then(|url| Url::parse(url).ok())
+        .map(|url| url.origin().ascii_serialization())
+        .filter(|origin| origin != "null");
+    if let Some(website) = website {
+        elements.push(element(1, "website", &website));
+    }
+
+    write_nfo(&dir.join(ARTIST_NFO), "artist", &elements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::structs::release_page::{ReleaseInfo, TrackInfo};
+    use crate::test_util::{item, temp_dir};
+
+    #[test]
+    fn escapes_xml() {
+        assert_eq!(
+            escape(r#"Rock & Roll <Live> "Tom's""#),
+            "Rock &amp; Roll &lt;Live&gt; &quot;Tom&apos;s&quot;"
+        );
+        assert_eq!(escape("a\u{0}b\u{1b}c\nd\te"), "abc\nd\te");
+        assert_eq!(escape("Café ☕"), "Café ☕");
+    }
+
+    #[test]
+    fn writes_escaped_album_nfo() {
+        let dir = temp_dir("nfo-album");
+        let item = item("Tom & Jerry", "<Live> at \"The Bar\"");
+        let page = ReleasePage {
+            current: ReleaseInfo {
+                about: Some("  Recorded live.\u{7}  ".to_string()),
+                credits: None,
+            },
+            trackinfo: vec![
+                TrackInfo {
+                    track_num: Some(1),
+                    title: Some("Intro & Outro".to_string()),
+                    duration: Some(61.4),
+                    lyrics: None,
+                },
+                TrackInfo {
+                    track_num: None,
+                    title: None,
+                    duration: Some(0.0),
+                    lyrics: None,
+                },
+            ],
+            url: None,
+            tags: vec!["rock & roll".to_string()],
+        };
+        let info = AlbumInfo {
+            item: &item,
+            label: Some("Cat's Records"),
+            url: Some("https://tj.bandcamp.com/album/live?a=1&b=2"),
+            cover: Some("cover.jpg"),
+            page: Some(&page),
+        };
+
+        let path = write_album_nfo(&dir, &info).unwrap();
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<album>
+  <title>&lt;Live&gt; at &quot;The Bar&quot;</title>
+  <artist>Tom &amp; Jerry</artist>
+  <albumartist>Tom &amp; Jerry</albumartist>
+  <year>2021</year>
+  <releasedate>2021-03-02</releasedate>
+  <label>Cat&apos;s Records</label>
+  <genre>rock &amp; roll</genre>
+  <review>Recorded live.</review>
+  <website>https://tj.bandcamp.com/album/live?a=1&amp;b=2</website>
+  <thumb aspect="front">cover.jpg</thumb>
+  <track>
+    <position>1</position>
+    <title>Intro &amp; Outro</title>
+    <duration>1:01</duration>
+  </track>
+  <track>
+    <position>2</position>
+  </track>
+</album>
+"#
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writes_artist_nfo_with_their_page() {
+        let dir = temp_dir("nfo-artist");
+
+        write_artist_nfo(
+            &dir,
+            "Tom & Jerry",
+            Some("https://tj.bandcamp.com/album/live"),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join(ARTIST_NFO)).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<artist>
+  <name>Tom &amp; Jerry</name>
+  <website>https://tj.bandcamp.com</website>
+</artist>
+"#
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+use chrono::{NaiveDateTime, Utc};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    api::structs::{digital_item, DigitalItem},
+    cache::Release,
+    extract::{self, Track},
+    layout::Layout,
+    sanitise::truncate_name,
+};
+
+/// Folder in the output folder that collection-wide playlists are saved in.
+pub const PLAYLISTS_FOLDER: &str = "Playlists";
+/// Subfolder of `PLAYLISTS_FOLDER` with a playlist for each artist.
+const ARTISTS_FOLDER: &str = "Artists";
+/// How many days back the recent purchases playlist goes.
+const RECENT_DAYS: i64 = 30;
+
+/// `#EXTINF` line and path for a track, relative to the playlist's folder.
+fn entry(track: &Track, playlist_dir: &Path, item: &DigitalItem) -> String {
+    format!(
+        "#EXTINF:-1,{} - {}\n{}\n",
+        item.artist,
+        track.title,
+        relative_path(&track.file, playlist_dir)
+    )
+}
+
+/// Path of `path` relative to `base`, with `/` separators so playlists work on
+/// any platform.
+fn relative_path(path: &Path, base: &Path) -> String {
+    let path = path.components().collect::<Vec<_>>();
+    let base = base.components().collect::<Vec<_>>();
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+
+    base[common..]
+        .iter()
+        .map(|_| String::from(".."))
+        .chain(path[common..].iter().filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        }))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Write a playlist, leaving it alone if it hasn't changed.
+fn write_playlist(path: &Path, entries: &[String]) -> Result<(), Box<dyn Error>> {
+    let content = format!("#EXTM3U\n{}", entries.concat());
+    if fs::read_to_string(path).is_ok_and(|existing| existing == content) {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, content)?;
+
+    Ok(())
+}
+
+/// Write a playlist for a release into its folder, named after the folder.
+pub fn write_release_playlist(
+    dir: &Path,
+    tracks: &[Track],
+    item: &DigitalItem,
+) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    let Some(name) = dir.file_name() else {
+        return Ok(None);
+    };
+    let path = dir.join(format!("{}.m3u8", name.to_string_lossy()));
+    let entries = tracks
+        .iter()
+        .map(|track| entry(track, dir, item))
+        .collect::<Vec<_>>();
+
+    write_playlist(&path, &entries)?;
+
+    Ok(Some(path))
+}
+
+/// Regenerate the collection-wide playlists from every recorded release that's
+/// still on disk: one with everything, one with recent purchases, and one for
+/// each artist.
+pub fn write_collection_playlists(
+    root: &Path,
+    releases: &HashMap<String, Release>,
+    layout: &Layout,
+) -> Result<(), Box<dyn Error>> {
+    let folder = root.join(PLAYLISTS_FOLDER);
+    let artists_folder = folder.join(ARTISTS_FOLDER);
+
+    let mut releases = releases.values().collect::<Vec<_>>();
+    releases.sort_by(|a, b| a.path.cmp(&b.path).then_with(|| a.id.cmp(&b.id)));
+
+    // Releases recorded before their tracks were are worked out from their
+    // files instead.
+    let tracks = |release: &Release| {
+        if release.tracks.is_empty() {
+            let files = release.files_on_disk(root);
+            extract::identify_tracks(&files, &release.item, None, layout)
+        } else {
+            release.tracks_on_disk(root)
+        }
+    };
+    let entries = |releases: &[&Release], playlist_dir: &Path| {
+        releases
+            .iter()
+            .flat_map(|release| {
+                tracks(release)
+                    .iter()
+                    .map(|track| entry(track, playlist_dir, &release.item))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    write_playlist(&folder.join("All.m3u8"), &entries(&releases, &folder))?;
+
+    let now = Utc::now().naive_utc();
+    let mut recent = releases
+        .iter()
+        .filter_map(|release| {
+            let purchased = release.purchased.as_deref()?;
+            let purchased = NaiveDateTime::parse_from_str(purchased, digital_item::FORMAT).ok()?;
+            ((now - purchased).num_days() < RECENT_DAYS).then_some((purchased, *release))
+        })
+        .collect::<Vec<_>>();
+    recent.sort_by_key(|(purchased, _)| Reverse(*purchased));
+    let recent = recent.into_iter().map(|(_, r)| r).collect::<Vec<_>>();
+    write_playlist(
+        &folder.join("Recently Purchased.m3u8"),
+        &entries(&recent, &folder),
+    )?;
+
+    let mut artists = BTreeMap::<String, Vec<&Release>>::new();
+    let sanitiser = layout.sanitiser();
+    for release in &releases {
+        let name = sanitiser.sanitise(&release.item.artist);
+        let name = if name.is_empty() {
+            String::from("_")
+        } else {
+            name
+        };
+        let name = truncate_name(&format!("{name}.m3u8"), layout.max_name_bytes.into());
+        artists.entry(name).or_default().push(release);
+    }
+
+    for (name, releases) in &artists {
+        write_playlist(
+            &artists_folder.join(name),
+            &entries(releases, &artists_folder),
+        )?;
+    }
+
+    // Clear out playlists for artists that are no longer in the collection.
+    let Ok(existing) = fs::read_dir(&artists_folder) else {
+        return Ok(());
+    };
+    for entry in existing.filter_map(Result::ok) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.ends_with(".m3u8") && !artists.contains_key(&name) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{item, temp_dir};
+
+    #[test]
+    fn makes_paths_relative() {
+        let relative = |path, base| relative_path(Path::new(path), Path::new(base));
+        assert_eq!(relative("/music/A/B/1.flac", "/music/A/B"), "1.flac");
+        assert_eq!(
+            relative("/music/A/B/1.flac", "/music/Playlists"),
+            "../A/B/1.flac"
+        );
+        assert_eq!(
+            relative("/music/A/B/1.flac", "/music/Playlists/Artists"),
+            "../../A/B/1.flac"
+        );
+        assert_eq!(relative("/music/A/B/C/1.flac", "/music/A"), "B/C/1.flac");
+    }
+
+    #[test]
+    fn lists_renamed_tracks_in_order() {
+        let dir = temp_dir("playlist");
+        let tracks = [(1, "First"), (2, "Second"), (10, "Tenth")].map(|(number, title)| Track {
+            number,
+            title: title.to_string(),
+            file: dir.join(format!("{title} ({number}).flac")),
+        });
+
+        let path = write_release_playlist(&dir, &tracks, &item("Artist", "Album"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            "#EXTM3U\n\
+             #EXTINF:-1,Artist - First\nFirst (1).flac\n\
+             #EXTINF:-1,Artist - Second\nSecond (2).flac\n\
+             #EXTINF:-1,Artist - Tenth\nTenth (10).flac\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+use ebur128::{EbuR128, Mode};
+use lofty::{file::TaggedFileExt, prelude::ItemKey};
+use std::{error::Error, fs::File, io::ErrorKind, path::Path};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error as DecodeError,
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+use crate::tags::Field;
+
+/// Loudness that ReplayGain 2.0 adjusts tracks to, in LUFS.
+const REFERENCE_LOUDNESS: f64 = -18.0;
+
+/// Tags to write to a track.
+type Fields = Vec<(Field, String)>;
+
+/// Loudness measurements of a single track.
+pub struct Analysis {
+    state: EbuR128,
+    peak: f64,
+}
+
+/// Decode a track and measure its loudness.
+pub fn analyse(path: &Path) -> Result<Analysis, Box<dyn Error>> {
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+
+    let Some(track) = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+    else {
+        bail!("no audio track found");
+    };
+    let track_id = track.id;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut state: Option<EbuR128> = None;
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(DecodeError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(DecodeError::ResetRequired) => {
+                decoder.reset();
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // Skip over corrupt packets, like most players would.
+            Err(DecodeError::DecodeError(_)) => continue,
+            Err(DecodeError::ResetRequired) => {
+                decoder.reset();
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let spec = *decoded.spec();
+        let channels = spec.channels.count();
+
+        let state = match &mut state {
+            Some(state) => state,
+            None => state.insert(EbuR128::new(
+                channels as u32,
+                spec.rate,
+                Mode::I | Mode::SAMPLE_PEAK,
+            )?),
+        };
+        let buffer = match &mut buffer {
+            Some(buffer) if buffer.capacity() >= decoded.capacity() * channels => buffer,
+            _ => buffer.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+
+        buffer.copy_interleaved_ref(decoded);
+        state.add_frames_f32(buffer.samples())?;
+    }
+
+    let Some(state) = state else {
+        bail!("track has no audio");
+    };
+    let peak = (0..state.channels())
+        .map(|channel| state.sample_peak(channel))
+        .try_fold(0.0_f64, |peak, p| p.map(|p| peak.max(p)))?;
+
+    Ok(Analysis { state, peak })
+}
+
+/// Gain and peak tags for a loudness measurement, if there is one. Silent or
+/// empty audio measures as -inf LUFS, which has no sensible gain.
+fn gain_fields(
+    gain_field: &'static str,
+    peak_field: &'static str,
+    loudness: f64,
+    peak: f64,
+) -> Option<[(Field, String); 2]> {
+    let gain = REFERENCE_LOUDNESS - loudness;
+    (gain.is_finite() && peak.is_finite()).then(|| {
+        [
+            (Field::Custom(gain_field), format!("{gain:.2} dB")),
+            (Field::Custom(peak_field), format!("{peak:.6}")),
+        ]
+    })
+}
+
+/// ReplayGain tags for each of a release's tracks, with the album gain and peak
+/// measured over all of them together. Tracks, or the whole album, that are
+/// silent are left without them.
+pub fn release_fields(tracks: &[Analysis]) -> Result<Vec<Fields>, Box<dyn Error>> {
+    let album_loudness = EbuR128::loudness_global_multiple(tracks.iter().map(|t| &t.state))?;
+    let album_peak = tracks.iter().map(|t| t.peak).fold(0.0, f64::max);
+    let album = gain_fields(
+        "REPLAYGAIN_ALBUM_GAIN",
+        "REPLAYGAIN_ALBUM_PEAK",
+        album_loudness,
+        album_peak,
+    );
+
+    tracks
+        .iter()
+        .map(|track| {
+            let track = gain_fields(
+                "REPLAYGAIN_TRACK_GAIN",
+                "REPLAYGAIN_TRACK_PEAK",
+                track.state.loudness_global()?,
+                track.peak,
+            );
+            Ok(track.into_iter().chain(album.clone()).flatten().collect())
+        })
+        .collect()
+}
+
+/// Whether a track already has ReplayGain tags for its album.
+pub fn has_album_gain(path: &Path) -> bool {
+    lofty::read_from_path(path).is_ok_and(|file| {
+        file.tags()
+            .iter()
+            .any(|tag| tag.get(&ItemKey::ReplayGainAlbumGain).is_some())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{sine, temp_dir, wav};
+
+    fn names(fields: &Fields) -> Vec<(&str, &str)> {
+        fields
+            .iter()
+            .map(|(field, value)| match field {
+                Field::Custom(name) => (*name, value.as_str()),
+                Field::AlbumArtist => ("ALBUMARTIST", value.as_str()),
+            })
+            .collect()
+    }
+
+    fn analysis(name: &str, samples: &[f32]) -> Analysis {
+        let dir = temp_dir(&format!("replaygain-{name}"));
+        let path = dir.join("track.wav");
+        wav(&path, 48000, samples);
+        let analysis = analyse(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        analysis
+    }
+
+    #[test]
+    fn gain_is_relative_to_the_reference() {
+        let [(_, gain), (_, peak)] =
+            gain_fields("GAIN", "PEAK", -23.0, 0.5).expect("finite loudness");
+        assert_eq!(gain, "5.00 dB");
+        assert_eq!(peak, "0.500000");
+
+        let [(_, gain), _] = gain_fields("GAIN", "PEAK", -8.5, 1.0).unwrap();
+        assert_eq!(gain, "-9.50 dB");
+    }
+
+    #[test]
+    fn silence_has_no_gain() {
+        assert!(gain_fields("GAIN", "PEAK", f64::NEG_INFINITY, 0.0).is_none());
+        assert!(gain_fields("GAIN", "PEAK", f64::NAN, 0.0).is_none());
+    }
+
+    #[test]
+    fn measures_decoded_tracks() {
+        // A 1 kHz sine measures 3 LU below its peak level.
+        let track = analysis("sine", &sine(0.5));
+        assert!((track.peak - 0.5).abs() < 0.001, "{}", track.peak);
+        let loudness = track.state.loudness_global().unwrap();
+        assert!(
+            (loudness - (20.0 * 0.5f64.log10() - 3.01)).abs() < 0.1,
+            "{loudness}"
+        );
+    }
+
+    #[test]
+    fn leaves_silent_tracks_untagged() {
+        let tracks = [
+            analysis("loud", &sine(0.5)),
+            analysis("silent", &[0.0; 48000]),
+        ];
+        let fields = release_fields(&tracks).unwrap();
+
+        let loud = names(&fields[0]);
+        assert_eq!(
+            loud.iter().map(|f| f.0).collect::<Vec<_>>(),
+            [
+                "REPLAYGAIN_TRACK_GAIN",
+                "REPLAYGAIN_TRACK_PEAK",
+                "REPLAYGAIN_ALBUM_GAIN",
+                "REPLAYGAIN_ALBUM_PEAK"
+            ]
+        );
+        // Silence doesn't count towards the album's loudness.
+        assert_eq!(loud[0].1, loud[2].1);
+        assert_eq!(
+            names(&fields[1]).iter().map(|f| f.0).collect::<Vec<_>>(),
+            ["REPLAYGAIN_ALBUM_GAIN", "REPLAYGAIN_ALBUM_PEAK"]
+        );
+
+        let silent = release_fields(&[analysis("all-silent", &[0.0; 48000])]).unwrap();
+        assert!(silent[0].is_empty());
+    }
+}
@@ -0,0 +1,445 @@
+use clap::ValueEnum;
+use deunicode::deunicode;
+use phf::phf_map;
+use std::str::FromStr;
+use unicode_normalization::UnicodeNormalization;
+
+// From https://github.com/Ezwen/bandcamp-collection-downloader/blob/master/src/main/kotlin/bandcampcollectiondownloader/core/Constants.kt#L7
+static REPLACEMENT_CHARS: phf::Map<char, &str> = phf_map! {
+    ':' => "꞉",
+    '/' => "／",
+    '\\' => "⧹",
+    '"' => "＂",
+    '*' => "⋆",
+    '<' => "＜",
+    '>' => "＞",
+    '?' => "？",
+    '|' => "∣",
+};
+
+// Plain ASCII equivalents of the above, for `--ascii`.
+static ASCII_REPLACEMENT_CHARS: phf::Map<char, &str> = phf_map! {
+    ':' => "-",
+    '/' => "-",
+    '\\' => "-",
+    '"' => "'",
+    '*' => "_",
+    '<' => "(",
+    '>' => ")",
+    '?' => "_",
+    '|' => "-",
+};
+
+// NTFS doesn't like these and pretty much shits itself if you try to do
+// anything to files/folders containing em.
+static UNSAFE_NTFS_ENDINGS: &[char] = &['.', ' '];
+
+// Names Windows reserves for devices, regardless of any extension.
+static RESERVED_WINDOWS_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// FAT32 limits names to 255 UTF-16 code units.
+const FAT32_MAX_NAME_UNITS: usize = 255;
+
+/// Which filesystem generated names need to be safe for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum FsProfile {
+    /// Only replaces `/`, for Linux and macOS filesystems.
+    Posix,
+    /// Replaces characters Windows doesn't allow, and avoids reserved names
+    /// like `CON` or names ending in a space or full stop. Also covers
+    /// network shares served over SMB, which follow the same rules.
+    #[default]
+    Ntfs,
+    /// Same as `ntfs`, while also keeping names within FAT32's length limit.
+    #[value(alias = "vfat")]
+    Fat32,
+}
+
+/// Unicode normalisation form applied to generated names.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Normalisation {
+    /// Leave names as Bandcamp gives them.
+    None,
+    /// Canonical composition, used by most Linux and Windows software.
+    #[default]
+    Nfc,
+    /// Canonical decomposition, similar to what macOS uses.
+    Nfd,
+    /// Compatibility composition.
+    Nfkc,
+    /// Compatibility decomposition.
+    Nfkd,
+}
+
+impl Normalisation {
+    pub fn apply(&self, s: &str) -> String {
+        match self {
+            Self::None => s.to_string(),
+            Self::Nfc => s.nfc().collect(),
+            Self::Nfd => s.nfd().collect(),
+            Self::Nfkc => s.nfkc().collect(),
+            Self::Nfkd => s.nfkd().collect(),
+        }
+    }
+}
+
+/// A user-provided `FROM=TO` override for the replacement map.
+#[derive(Clone, Debug)]
+pub struct Replacement {
+    from: char,
+    to: String,
+}
+
+impl FromStr for Replacement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| format!("replacement `{s}` should be in the form `FROM=TO`"))?;
+        let mut chars = from.chars();
+
+        if to.contains('/') {
+            return Err(format!("replacement `{s}` cannot contain `/`"));
+        }
+
+        match (chars.next(), chars.next()) {
+            (Some(from), None) => Ok(Self {
+                from,
+                to: to.to_string(),
+            }),
+            _ => Err(format!(
+                "replacement `{s}` should replace a single character"
+            )),
+        }
+    }
+}
+
+/// Makes strings safe to use as file or folder names.
+pub struct Sanitiser {
+    profile: FsProfile,
+    replacements: Vec<Replacement>,
+    ascii: bool,
+    normalisation: Normalisation,
+}
+
+impl Sanitiser {
+    /// Create a sanitiser for a filesystem profile. If `ascii` is set, names
+    /// are also transliterated into plain ASCII.
+    pub fn new(profile: FsProfile, overrides: &[Replacement], ascii: bool) -> Self {
+        let map = if ascii {
+            &ASCII_REPLACEMENT_CHARS
+        } else {
+            &REPLACEMENT_CHARS
+        };
+        let mut replacements = map
+            .entries()
+            .filter(|(from, _)| profile != FsProfile::Posix || **from == '/')
+            .map(|(from, to)| Replacement {
+                from: *from,
+                to: to.to_string(),
+            })
+            .collect::<Vec<_>>();
+
+        for replacement in overrides {
+            replacements.retain(|r| r.from != replacement.from);
+            replacements.push(Replacement {
+                from: replacement.from,
+                to: if ascii {
+                    deunicode(&replacement.to)
+                } else {
+                    replacement.to.clone()
+                },
+            });
+        }
+
+        Self {
+            profile,
+            replacements,
+            ascii,
+            normalisation: Normalisation::None,
+        }
+    }
+
+    /// Normalise names to the given Unicode normalisation form.
+    pub fn with_normalisation(mut self, normalisation: Normalisation) -> Self {
+        self.normalisation = normalisation;
+        self
+    }
+
+    pub fn sanitise(&self, s: &str) -> String {
+        let s = if self.ascii {
+            deunicode(s)
+        } else {
+            self.normalisation.apply(s)
+        };
+        let mut str = String::with_capacity(s.len());
+
+        for c in s.chars() {
+            if let Some(replacement) = self.replacements.iter().find(|r| r.from == c) {
+                str.push_str(&replacement.to);
+            } else if !c.is_control() {
+                str.push(c);
+            }
+        }
+
+        // `.` and `..` would point somewhere else entirely.
+        if !str.is_empty() && str.chars().all(|c| c == '.') {
+            str.push('_');
+        }
+
+        if self.profile == FsProfile::Posix {
+            return str;
+        }
+
+        if self.profile == FsProfile::Fat32 {
+            // Leave room for the suffixes added below.
+            truncate_utf16(&mut str, FAT32_MAX_NAME_UNITS - 2);
+        }
+
+        if str.ends_with(UNSAFE_NTFS_ENDINGS) {
+            str.push('_');
+        }
+
+        let base = str.split('.').next().unwrap_or_default().trim_end();
+        if RESERVED_WINDOWS_NAMES
+            .iter()
+            .any(|name| base.eq_ignore_ascii_case(name))
+        {
+            str.insert(base.len(), '_');
+        }
+
+        str
+    }
+}
+
+/// Truncate a string on a character boundary so it fits in `max` UTF-16 code
+/// units.
+fn truncate_utf16(s: &mut String, max: usize) {
+    let mut units = 0;
+    for (i, c) in s.char_indices() {
+        units += c.len_utf16();
+        if units > max {
+            s.truncate(i);
+            return;
+        }
+    }
+}
+
+/// Truncate a name to fit within `max` bytes, cutting on a UTF-8 boundary.
+/// A trailing ` (...)` suffix, like a release year, or a file extension is kept
+/// intact, and a hash of the full name is added so that different names that
+/// truncate to the same prefix stay unique.
+pub fn truncate_name(name: &str, max: usize) -> String {
+    if name.len() <= max {
+        return name.to_string();
+    }
+
+    let hash = format!("~{:08x}", fnv1a(name.as_bytes()));
+    let suffix_start = if name.ends_with(')') {
+        name.rfind(" (")
+    } else {
+        name.rfind('.')
+            .filter(|i| name.len() - i <= MAX_EXTENSION_LEN)
+    };
+    let (head, suffix) = match suffix_start {
+        Some(i) if name.len() - i + hash.len() < max => name.split_at(i),
+        _ => (name, ""),
+    };
+
+    let mut end = max
+        .saturating_sub(suffix.len() + hash.len())
+        .min(head.len());
+    while !head.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("{}{hash}{suffix}", head[..end].trim_end())
+}
+
+// Longest file extension (including the dot) kept when truncating names.
+const MAX_EXTENSION_LEN: usize = 8;
+
+/// 32-bit FNV-1a, used as it's stable across Rust versions and platforms.
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, b| {
+        (hash ^ u32::from(*b)).wrapping_mul(0x01000193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitise(profile: FsProfile, s: &str) -> String {
+        Sanitiser::new(profile, &[], false).sanitise(s)
+    }
+
+    #[test]
+    fn posix_only_replaces_slashes() {
+        assert_eq!(sanitise(FsProfile::Posix, "a/b:c?*|"), "a／b:c?*|");
+        assert_eq!(sanitise(FsProfile::Posix, "CON"), "CON");
+        assert_eq!(sanitise(FsProfile::Posix, "trailing. "), "trailing. ");
+    }
+
+    #[test]
+    fn windows_profiles_replace_reserved_characters() {
+        for profile in [FsProfile::Ntfs, FsProfile::Fat32] {
+            assert_eq!(
+                sanitise(profile, r#"a/b\c:d*e?f"g<h>i|j"#),
+                "a／b⧹c꞉d⋆e？f＂g＜h＞i∣j"
+            );
+        }
+    }
+
+    #[test]
+    fn windows_profiles_avoid_reserved_names() {
+        for profile in [FsProfile::Ntfs, FsProfile::Fat32] {
+            assert_eq!(sanitise(profile, "CON"), "CON_");
+            assert_eq!(sanitise(profile, "com1.txt"), "com1_.txt");
+            assert_eq!(sanitise(profile, "Aux .tar.gz"), "Aux_ .tar.gz");
+            assert_eq!(sanitise(profile, "CONSOLE"), "CONSOLE");
+            assert_eq!(sanitise(profile, "name."), "name._");
+            assert_eq!(sanitise(profile, "name "), "name _");
+        }
+    }
+
+    #[test]
+    fn never_makes_relative_names() {
+        for profile in [FsProfile::Posix, FsProfile::Ntfs] {
+            assert_eq!(sanitise(profile, "."), "._");
+            assert_eq!(sanitise(profile, ".."), ".._");
+            assert_eq!(sanitise(profile, "..."), "..._");
+            assert_eq!(sanitise(profile, ""), "");
+        }
+    }
+
+    #[test]
+    fn strips_control_characters() {
+        assert_eq!(sanitise(FsProfile::Posix, "a\u{0}b\nc\u{7f}d"), "abcd");
+        assert_eq!(sanitise(FsProfile::Ntfs, "a\tb"), "ab");
+    }
+
+    #[test]
+    fn fat32_limits_utf16_length() {
+        let name = sanitise(FsProfile::Fat32, &"é".repeat(300));
+        assert_eq!(name.chars().count(), FAT32_MAX_NAME_UNITS - 2);
+
+        // Characters outside the BMP take two units each.
+        let name = sanitise(FsProfile::Fat32, &"😀".repeat(300));
+        assert_eq!(name.encode_utf16().count(), FAT32_MAX_NAME_UNITS - 3);
+
+        let name = sanitise(FsProfile::Ntfs, &"é".repeat(300));
+        assert_eq!(name.chars().count(), 300);
+    }
+
+    #[test]
+    fn applies_overrides() {
+        let overrides = [":=-".parse().unwrap(), "x=y".parse().unwrap()];
+        let sanitiser = Sanitiser::new(FsProfile::Ntfs, &overrides, false);
+        assert_eq!(sanitiser.sanitise("a:x?"), "a-y？");
+
+        // Overrides apply to characters posix would otherwise leave alone.
+        let sanitiser = Sanitiser::new(FsProfile::Posix, &overrides, false);
+        assert_eq!(sanitiser.sanitise("a:x?"), "a-y?");
+    }
+
+    #[test]
+    fn rejects_invalid_overrides() {
+        for s in ["ab=c", "=c", "c", ":=/", ":=a/b"] {
+            assert!(
+                s.parse::<Replacement>().is_err(),
+                "`{s}` should be rejected"
+            );
+        }
+        assert!(":=".parse::<Replacement>().is_ok());
+    }
+
+    #[test]
+    fn transliterates_to_ascii() {
+        let sanitiser = Sanitiser::new(FsProfile::Ntfs, &[], true);
+        assert_eq!(
+            sanitiser.sanitise("Café: Über/Straße?"),
+            "Cafe- Uber-Strasse_"
+        );
+
+        let overrides = [":=꞉".parse().unwrap()];
+        let sanitiser = Sanitiser::new(FsProfile::Ntfs, &overrides, true);
+        assert!(sanitiser.sanitise("a:b").is_ascii());
+    }
+
+    #[test]
+    fn normalises_before_replacing() {
+        let sanitiser =
+            Sanitiser::new(FsProfile::Ntfs, &[], false).with_normalisation(Normalisation::Nfkc);
+        assert_eq!(sanitiser.sanitise("a／b？ ﬁ"), "a／b？ fi");
+
+        let sanitiser =
+            Sanitiser::new(FsProfile::Ntfs, &[], false).with_normalisation(Normalisation::Nfc);
+        assert_eq!(sanitiser.sanitise("Cafe\u{301}"), "Caf\u{e9}");
+    }
+
+    #[test]
+    fn truncate_leaves_short_names_alone() {
+        assert_eq!(truncate_name("short.flac", 255), "short.flac");
+        assert_eq!(truncate_name(&"a".repeat(255), 255), "a".repeat(255));
+    }
+
+    #[test]
+    fn truncate_fits_in_max_bytes() {
+        for max in [32, 100, 255] {
+            let name = truncate_name(&"a".repeat(300), max);
+            assert_eq!(name.len(), max);
+            assert!(name.contains('~'));
+        }
+    }
+
+    #[test]
+    fn truncate_cuts_on_char_boundaries() {
+        for max in 32..64 {
+            let name = truncate_name(&"日本".repeat(100), max);
+            assert!(name.len() <= max, "{name}");
+            assert!(name.starts_with("日本"), "{name}");
+        }
+    }
+
+    #[test]
+    fn truncate_keeps_suffixes() {
+        let name = truncate_name(&format!("{} (2021)", "t".repeat(300)), 100);
+        assert!(name.len() <= 100);
+        assert!(name.ends_with(" (2021)"), "{name}");
+
+        let name = truncate_name(&format!("{}.flac", "t".repeat(300)), 100);
+        assert!(name.len() <= 100);
+        assert!(name.ends_with(".flac"), "{name}");
+
+        // Anything too long to be an extension is cut like the rest.
+        let name = truncate_name(&format!("{}.notanextension", "t".repeat(300)), 100);
+        assert_eq!(name.len(), 100);
+        assert!(!name.ends_with(".notanextension"), "{name}");
+    }
+
+    #[test]
+    fn truncate_keeps_names_unique() {
+        let base = "t".repeat(300);
+        let a = truncate_name(&format!("{base} A"), 64);
+        let b = truncate_name(&format!("{base} B"), 64);
+        assert_ne!(a, b);
+        assert_eq!(a, truncate_name(&format!("{base} A"), 64));
+    }
+
+    #[test]
+    fn truncate_trims_trailing_spaces() {
+        let name = truncate_name(&format!("{}  {}", "t".repeat(22), "u".repeat(100)), 32);
+        assert!(name.starts_with(&format!("{}~", "t".repeat(22))), "{name}");
+    }
+
+    #[test]
+    fn fnv1a_is_stable() {
+        assert_eq!(fnv1a(b""), 0x811c9dc5);
+        assert_eq!(fnv1a(b"a"), 0xe40c292c);
+    }
+}
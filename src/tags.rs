@@ -0,0 +1,330 @@
+use lofty::{
+    config::{ParseOptions, WriteOptions},
+    file::{AudioFile, FileType},
+    flac::FlacFile,
+    id3::v2::{Frame, FrameId, Id3v2Tag, TextInformationFrame},
+    iff::{aiff::AiffFile, wav::WavFile},
+    mp4::{Atom, AtomData, AtomIdent, Ilst, Mp4File},
+    mpeg::MpegFile,
+    ogg::{OggPictureStorage, VorbisComments, VorbisFile},
+    picture::{Picture, PictureType},
+    probe::Probe,
+    TextEncoding,
+};
+use std::{borrow::Cow, error::Error, fs::File, path::Path};
+
+/// A tag to write to audio files, mapped to the right field for each format.
+#[derive(Clone, Debug)]
+pub enum Field {
+    /// The standard album artist field. Only written if the file doesn't
+    /// already have one.
+    AlbumArtist,
+    /// A custom field, named in the style of a Vorbis comment, e.g.
+    /// `BANDCAMP_URL`. Written as a `TXXX` frame in ID3v2 tags, and as a
+    /// freeform `com.apple.iTunes` atom in MP4 files.
+    Custom(&'static str),
+}
+
+/// Tag formats that fields can be written to.
+trait Tags {
+    fn has(&self, field: &Field) -> bool;
+    fn set(&mut self, field: &Field, value: String);
+}
+
+impl Tags for VorbisComments {
+    fn has(&self, field: &Field) -> bool {
+        match field {
+            Field::AlbumArtist => self.get("ALBUMARTIST").is_some(),
+            Field::Custom(key) => self.get(key).is_some(),
+        }
+    }
+
+    fn set(&mut self, field: &Field, value: String) {
+        match field {
+            Field::AlbumArtist => self.insert(String::from("ALBUMARTIST"), value),
+            Field::Custom(key) => self.insert(key.to_string(), value),
+        }
+    }
+}
+
+const ALBUM_ARTIST_FRAME: FrameId<'static> = FrameId::Valid(Cow::Borrowed("TPE2"));
+
+impl Tags for Id3v2Tag {
+    fn has(&self, field: &Field) -> bool {
+        match field {
+            Field::AlbumArtist => self.get_text(&ALBUM_ARTIST_FRAME).is_some(),
+            Field::Custom(key) => self.get_user_text(key).is_some(),
+        }
+    }
+
+    fn set(&mut self, field: &Field, value: String) {
+        match field {
+            Field::AlbumArtist => {
+                self.insert(Frame::Text(TextInformationFrame::new(
+                    ALBUM_ARTIST_FRAME,
+                    TextEncoding::UTF8,
+                    value,
+                )));
+            }
+            Field::Custom(key) => {
+                self.insert_user_text(key.to_string(), value);
+            }
+        }
+    }
+}
+
+fn atom_ident(field: &Field) -> AtomIdent<'static> {
+    match field {
+        Field::AlbumArtist => AtomIdent::Fourcc(*b"aART"),
+        Field::Custom(key) => AtomIdent::Freeform {
+            mean: Cow::Borrowed("com.apple.iTunes"),
+            name: Cow::Borrowed(key),
+        },
+    }
+}
+
+impl Tags for Ilst {
+    fn has(&self, field: &Field) -> bool {
+        self.get(&atom_ident(field)).is_some()
+    }
+
+    fn set(&mut self, field: &Field, value: String) {
+        self.replace_atom(Atom::new(atom_ident(field), AtomData::UTF8(value)));
+    }
+}
+
+fn apply(tags: &mut impl Tags, fields: &[(Field, String)]) {
+    for (field, value) in fields {
+        if matches!(field, Field::AlbumArtist) && tags.has(field) {
+            continue;
+        }
+        tags.set(field, value.clone());
+    }
+}
+
+/// Read an audio file, change it, and save it back in place.
+fn edit<F: AudioFile>(
+    path: &Path,
+    change: impl FnOnce(&mut F) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut audio = {
+        let mut file = File::open(path)?;
+        F::read_from(&mut file, ParseOptions::new())?
+    };
+
+    change(&mut audio)?;
+    audio.save_to_path(path, WriteOptions::default())?;
+
+    Ok(())
+}
+
+/// Write fields into an audio file's tags, creating them if there aren't any.
+/// Returns whether the file was a format that could be tagged.
+pub fn write_fields(path: &Path, fields: &[(Field, String)]) -> Result<bool, Box<dyn Error>> {
+    let file_type = Probe::open(path)?.guess_file_type()?.file_type();
+
+    match file_type {
+        Some(FileType::Flac) => edit(path, |f: &mut FlacFile| {
+            if f.vorbis_comments().is_none() {
+                f.set_vorbis_comments(VorbisComments::default());
+            }
+            apply(f.vorbis_comments_mut().unwrap(), fields);
+            Ok(())
+        })?,
+        Some(FileType::Vorbis) => edit(path, |f: &mut VorbisFile| {
+            apply(f.vorbis_comments_mut(), fields);
+            Ok(())
+        })?,
+        Some(FileType::Mpeg) => edit(path, |f: &mut MpegFile| {
+            if f.id3v2().is_none() {
+                f.set_id3v2(Id3v2Tag::default());
+            }
+            apply(f.id3v2_mut().unwrap(), fields);
+            Ok(())
+        })?,
+        Some(FileType::Aiff) => edit(path, |f: &mut AiffFile| {
+            if f.id3v2().is_none() {
+                f.set_id3v2(Id3v2Tag::default());
+            }
+            apply(f.id3v2_mut().unwrap(), fields);
+            Ok(())
+        })?,
+        Some(FileType::Wav) => edit(path, |f: &mut WavFile| {
+            if f.id3v2().is_none() {
+                f.set_id3v2(Id3v2Tag::default());
+            }
+            apply(f.id3v2_mut().unwrap(), fields);
+            Ok(())
+        })?,
+        Some(FileType::Mp4) => edit(path, |f: &mut Mp4File| {
+            if f.ilst().is_none() {
+                f.set_ilst(Ilst::default());
+            }
+            apply(f.ilst_mut().unwrap(), fields);
+            Ok(())
+        })?,
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+/// Replace the front cover in an ID3v2 tag.
+fn replace_id3v2_cover(tag: &mut Id3v2Tag, picture: &Picture) {
+    tag.remove_picture_type(PictureType::CoverFront);
+    tag.insert_picture(picture.clone());
+}
+
+/// Embed a picture as an audio file's front cover, replacing any existing one.
+/// Returns whether the file was a format that could be tagged.
+pub fn embed_cover(path: &Path, picture: &Picture) -> Result<bool, Box<dyn Error>> {
+    let file_type = Probe::open(path)?.guess_file_type()?.file_type();
+
+    match file_type {
+        // FLAC stores pictures in their own blocks, but older files might have
+        // them in their Vorbis comments instead.
+        Some(FileType::Flac) => edit(path, |f: &mut FlacFile| {
+            if let Some(comments) = f.vorbis_comments_mut() {
+                comments.remove_picture_type(PictureType::CoverFront);
+            }
+            f.remove_picture_type(PictureType::CoverFront);
+            f.insert_picture(picture.clone(), None)?;
+            Ok(())
+        })?,
+        Some(FileType::Vorbis) => edit(path, |f: &mut VorbisFile| {
+            let comments = f.vorbis_comments_mut();
+            comments.remove_picture_type(PictureType::CoverFront);
+            comments.insert_picture(picture.clone(), None)?;
+            Ok(())
+        })?,
+        Some(FileType::Mpeg) => edit(path, |f: &mut MpegFile| {
+            if f.id3v2().is_none() {
+                f.set_id3v2(Id3v2Tag::default());
+            }
+            replace_id3v2_cover(f.id3v2_mut().unwrap(), picture);
+            Ok(())
+        })?,
+        Some(FileType::Aiff) => edit(path, |f: &mut AiffFile| {
+            if f.id3v2().is_none() {
+                f.set_id3v2(Id3v2Tag::default());
+            }
+            replace_id3v2_cover(f.id3v2_mut().unwrap(), picture);
+            Ok(())
+        })?,
+        Some(FileType::Wav) => edit(path, |f: &mut WavFile| {
+            if f.id3v2().is_none() {
+                f.set_id3v2(Id3v2Tag::default());
+            }
+            replace_id3v2_cover(f.id3v2_mut().unwrap(), picture);
+            Ok(())
+        })?,
+        // MP4 files only have the one kind of picture.
+        Some(FileType::Mp4) => edit(path, |f: &mut Mp4File| {
+            if f.ilst().is_none() {
+                f.set_ilst(Ilst::default());
+            }
+            let ilst = f.ilst_mut().unwrap();
+            ilst.remove_pictures();
+            ilst.insert_picture(picture.clone());
+            Ok(())
+        })?,
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{sine, temp_dir, wav};
+    use lofty::picture::MimeType;
+    use std::fs;
+
+    fn read_id3v2(path: &Path) -> Id3v2Tag {
+        let mut file = File::open(path).unwrap();
+        let wav = WavFile::read_from(&mut file, ParseOptions::new()).unwrap();
+        wav.id3v2().cloned().expect("should have an ID3v2 tag")
+    }
+
+    #[test]
+    fn writes_fields_that_can_be_read_back() {
+        let dir = temp_dir("tags-fields");
+        let path = dir.join("track.wav");
+        wav(&path, 48000, &sine(0.5));
+
+        let fields = [
+            (Field::AlbumArtist, "Artist".to_string()),
+            (
+                Field::Custom("BANDCAMP_URL"),
+                "https://a.bandcamp.com".to_string(),
+            ),
+        ];
+        assert!(write_fields(&path, &fields).unwrap());
+        let tag = read_id3v2(&path);
+        assert_eq!(tag.get_text(&ALBUM_ARTIST_FRAME), Some("Artist"));
+        assert_eq!(
+            tag.get_user_text("BANDCAMP_URL"),
+            Some("https://a.bandcamp.com")
+        );
+
+        // Custom fields are replaced, but an existing album artist is kept.
+        let fields = [
+            (Field::AlbumArtist, "Someone Else".to_string()),
+            (
+                Field::Custom("BANDCAMP_URL"),
+                "https://b.bandcamp.com".to_string(),
+            ),
+        ];
+        write_fields(&path, &fields).unwrap();
+        let tag = read_id3v2(&path);
+        assert_eq!(tag.get_text(&ALBUM_ARTIST_FRAME), Some("Artist"));
+        assert_eq!(
+            tag.get_user_text("BANDCAMP_URL"),
+            Some("https://b.bandcamp.com")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replaces_the_embedded_cover() {
+        let dir = temp_dir("tags-cover");
+        let path = dir.join("track.wav");
+        wav(&path, 48000, &sine(0.5));
+
+        let cover = |data: &[u8]| {
+            Picture::new_unchecked(
+                PictureType::CoverFront,
+                Some(MimeType::Jpeg),
+                None,
+                data.to_vec(),
+            )
+        };
+        assert!(embed_cover(&path, &cover(b"first")).unwrap());
+        assert!(embed_cover(&path, &cover(b"second")).unwrap());
+
+        let pictures = read_id3v2(&path)
+            .into_iter()
+            .filter_map(|frame| match frame {
+                Frame::Picture(frame) => Some(frame.picture.into_data()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(pictures, [b"second".to_vec()]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skips_files_that_cant_be_tagged() {
+        let dir = temp_dir("tags-skip");
+        let path = dir.join("notes.txt");
+        fs::write(&path, "not audio").unwrap();
+
+        assert!(!write_fields(&path, &[(Field::AlbumArtist, "Artist".to_string())]).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not audio");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
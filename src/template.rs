@@ -0,0 +1,220 @@
+/// A value that can be substituted into a template placeholder.
+pub enum Value {
+    Text(String),
+    Number(u64),
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Placeholder { name: String, width: Option<usize> },
+}
+
+/// A user-provided template like `{artist}/{title} ({year})`, split into path
+/// components on `/`. Placeholders can take a zero-padded width for numbers,
+/// as in `{track:02}`, and literal braces are written as `{{` and `}}`.
+#[derive(Clone, Debug)]
+pub struct Template {
+    components: Vec<Vec<Segment>>,
+}
+
+impl Template {
+    /// Parse a template, only allowing the given placeholder names.
+    pub fn parse(source: &str, allowed: &[&str]) -> Result<Self, String> {
+        let mut components = vec![];
+
+        for component in source.split('/') {
+            if component.is_empty() || component == "." || component == ".." {
+                return Err(format!(
+                    "template `{source}` contains an empty or relative path component"
+                ));
+            }
+
+            let mut segments = vec![];
+            let mut literal = String::new();
+            let mut chars = component.chars().peekable();
+
+            while let Some(c) = chars.next() {
+                match c {
+                    '{' if chars.peek() == Some(&'{') => {
+                        chars.next();
+                        literal.push('{');
+                    }
+                    '}' if chars.peek() == Some(&'}') => {
+                        chars.next();
+                        literal.push('}');
+                    }
+                    '{' => {
+                        let mut inner = String::new();
+                        loop {
+                            match chars.next() {
+                                Some('}') => break,
+                                Some(c) => inner.push(c),
+                                None => {
+                                    return Err(format!("unclosed `{{` in template `{source}`"))
+                                }
+                            }
+                        }
+
+                        let (name, width) = match inner.split_once(':') {
+                            Some((name, width)) => {
+                                let width = width.parse::<usize>().map_err(|_| {
+                                    format!("invalid width `{width}` for `{{{name}}}` in template `{source}`")
+                                })?;
+                                (name, Some(width))
+                            }
+                            None => (inner.as_str(), None),
+                        };
+
+                        if !allowed.contains(&name) {
+                            return Err(format!(
+                                "unknown placeholder `{{{name}}}` in template `{source}`, expected one of: {}",
+                                allowed.iter().map(|n| format!("{{{n}}}")).collect::<Vec<_>>().join(", ")
+                            ));
+                        }
+
+                        if !literal.is_empty() {
+                            segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                        }
+                        segments.push(Segment::Placeholder {
+                            name: name.to_string(),
+                            width,
+                        });
+                    }
+                    '}' => return Err(format!("unmatched `}}` in template `{source}`")),
+                    c => literal.push(c),
+                }
+            }
+
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(literal));
+            }
+            components.push(segments);
+        }
+
+        Ok(Self { components })
+    }
+
+    /// Index of the first path component made up of nothing but the given
+    /// placeholder, like `{artist}`.
+    pub fn placeholder_component(&self, name: &str) -> Option<usize> {
+        self.components.iter().position(|segments| {
+            matches!(segments.as_slice(), [Segment::Placeholder { name: n, .. }] if n == name)
+        })
+    }
+
+    /// Number of path components in the template.
+    pub fn len(&self) -> usize {
+        self.components.len()
+    }
+
+    /// Render each path component of the template. Placeholder values are
+    /// passed through `escape` (e.g. to make them safe for the filesystem),
+    /// and literal text from the template through `literal`.
+    pub fn render<L, E, T>(&self, lookup: L, escape: E, literal: T) -> Vec<String>
+    where
+        L: Fn(&str) -> Value,
+        E: Fn(&str) -> String,
+        T: Fn(&str) -> String,
+    {
+        self.components
+            .iter()
+            .map(|segments| {
+                segments
+                    .iter()
+                    .map(|segment| match segment {
+                        Segment::Literal(text) => literal(text),
+                        Segment::Placeholder { name, width } => {
+                            let value = match (lookup(name), width) {
+                                (Value::Number(n), Some(width)) => format!("{n:0width$}"),
+                                (Value::Number(n), None) => n.to_string(),
+                                (Value::Text(text), _) => text,
+                            };
+                            escape(&value)
+                        }
+                    })
+                    .collect::<String>()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALLOWED: &[&str] = &["artist", "title", "track"];
+
+    fn render(source: &str) -> Vec<String> {
+        Template::parse(source, ALLOWED).unwrap().render(
+            |name| match name {
+                "track" => Value::Number(7),
+                name => Value::Text(format!("<{name}>")),
+            },
+            |value| value.replace('<', "(").replace('>', ")"),
+            |text| text.replace('-', "–"),
+        )
+    }
+
+    #[test]
+    fn renders_placeholders_per_component() {
+        assert_eq!(
+            render("{artist}/{title} - {track}"),
+            ["(artist)", "(title) – 7"]
+        );
+    }
+
+    #[test]
+    fn pads_numbers_to_width() {
+        assert_eq!(render("{track:03}"), ["007"]);
+        assert_eq!(render("{track:1}"), ["7"]);
+        // Widths don't apply to text.
+        assert_eq!(render("{title:10}"), ["(title)"]);
+    }
+
+    #[test]
+    fn escapes_placeholders_and_literals_separately() {
+        assert_eq!(render("<{title}>"), ["<(title)>"]);
+        assert_eq!(render("-{title}-"), ["–(title)–"]);
+    }
+
+    #[test]
+    fn unescapes_doubled_braces() {
+        assert_eq!(render("{{{title}}}"), ["{(title)}"]);
+        assert_eq!(render("{{track}}"), ["{track}"]);
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for source in [
+            "{title",
+            "title}",
+            "{album}",
+            "{}",
+            "{track:x}",
+            "{track:-1}",
+            "",
+            "{artist}//{title}",
+            "{artist}/",
+            "/{artist}",
+            "../{title}",
+            "{artist}/./{title}",
+        ] {
+            assert!(
+                Template::parse(source, ALLOWED).is_err(),
+                "`{source}` should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn finds_placeholder_components() {
+        let template = Template::parse("Music/{artist}/{title}", ALLOWED).unwrap();
+        assert_eq!(template.len(), 3);
+        assert_eq!(template.placeholder_component("artist"), Some(1));
+        assert_eq!(template.placeholder_component("title"), Some(2));
+
+        let template = Template::parse("{artist} - {title}", ALLOWED).unwrap();
+        assert_eq!(template.placeholder_component("artist"), None);
+    }
+}
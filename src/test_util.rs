@@ -0,0 +1,86 @@
+//! Helpers shared between tests.
+
+use clap::Parser;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{api::structs::DigitalItem, extract::Extras, layout::Layout};
+
+/// Options shared between commands, parsed like they are on the command line.
+#[derive(Parser)]
+pub struct Args {
+    #[command(flatten)]
+    pub layout: Layout,
+    #[command(flatten)]
+    pub extras: Extras,
+}
+
+pub fn args(args: &[&str]) -> Args {
+    Args::parse_from([&["bandsnatch"], args].concat())
+}
+
+pub fn layout(args: &[&str]) -> Layout {
+    self::args(args).layout
+}
+
+/// An album released in 2021.
+pub fn item(artist: &str, title: &str) -> DigitalItem {
+    DigitalItem {
+        downloads: None,
+        package_release_date: Some("02 Mar 2021 00:00:00 GMT".to_string()),
+        title: title.to_string(),
+        artist: artist.to_string(),
+        download_type: None,
+        download_type_str: "album".to_string(),
+        item_type: "album".to_string(),
+        art_id: None,
+    }
+}
+
+/// A single-track release in 2021.
+pub fn single(artist: &str, title: &str) -> DigitalItem {
+    DigitalItem {
+        download_type_str: "track".to_string(),
+        item_type: "track".to_string(),
+        ..item(artist, title)
+    }
+}
+
+/// An empty folder for a test to work in.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bandsnatch-test-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Write a mono 16-bit WAV file, with samples between -1 and 1.
+pub fn wav(path: &Path, rate: u32, samples: &[f32]) {
+    let data_len = samples.len() as u32 * 2;
+    let mut out = vec![];
+    out.extend(b"RIFF");
+    out.extend((36 + data_len).to_le_bytes());
+    out.extend(b"WAVEfmt ");
+    out.extend(16u32.to_le_bytes());
+    out.extend(1u16.to_le_bytes());
+    out.extend(1u16.to_le_bytes());
+    out.extend(rate.to_le_bytes());
+    out.extend((rate * 2).to_le_bytes());
+    out.extend(2u16.to_le_bytes());
+    out.extend(16u16.to_le_bytes());
+    out.extend(b"data");
+    out.extend(data_len.to_le_bytes());
+    for sample in samples {
+        out.extend(((sample * f32::from(i16::MAX)) as i16).to_le_bytes());
+    }
+    fs::write(path, out).unwrap();
+}
+
+/// A second of a 1 kHz sine wave, at 48 kHz.
+pub fn sine(amplitude: f32) -> Vec<f32> {
+    (0..48000)
+        .map(|i| amplitude * (i as f32 * 1000.0 * std::f32::consts::TAU / 48000.0).sin())
+        .collect()
+}
@@ -0,0 +1,319 @@
+use governor::{Quota, RateLimiter};
+use indicatif::ProgressBar;
+use pollster::FutureExt as _;
+use std::{
+    num::NonZeroU32,
+    sync::{Arc, Condvar, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+/// Slowest we'll go when Bandcamp keeps throttling us.
+const MIN_RATE: f64 = 0.25;
+/// Upper bound on the configured rate, so the limiter period never hits zero.
+const MAX_RATE: f64 = 1000.0;
+/// How much the request rate grows by after a streak of successful requests.
+const RATE_STEP: f64 = 0.5;
+/// Successful requests needed before trying to speed back up.
+const SUCCESSES_BEFORE_INCREASE: u32 = 20;
+/// Throttling responses within this window of a back-off for the same class of
+/// request are treated as part of the same burst, so parallel requests don't
+/// all halve the limits.
+const BACKOFF_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// The kind of request being made, each of which is limited separately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestClass {
+    /// Pages and API calls on bandcamp.com, which need to be kept polite.
+    Page,
+    /// File downloads from Bandcamp's CDN, which are only limited by
+    /// bandwidth.
+    Download,
+}
+
+/// How a class of request has been getting on since its limit last changed.
+#[derive(Default)]
+struct Backoff {
+    successes: u32,
+    last: Option<Instant>,
+}
+
+struct State {
+    jobs: usize,
+    active: usize,
+    rate: f64,
+    page: Backoff,
+    download: Backoff,
+}
+
+impl State {
+    fn backoff(&mut self, class: RequestClass) -> &mut Backoff {
+        match class {
+            RequestClass::Page => &mut self.page,
+            RequestClass::Download => &mut self.download,
+        }
+    }
+}
+
+/// Adaptive controller for how many downloads run at once and how quickly
+/// page requests are sent to Bandcamp. When Bandcamp responds with
+/// `429 Too Many Requests` the limit for that class of request is halved, and
+/// limits are slowly raised back towards the configured maximums while
+/// requests keep succeeding.
+pub struct Throttle {
+    max_jobs: usize,
+    max_rate: f64,
+    state: Mutex<State>,
+    slot_freed: Condvar,
+    limiter: RwLock<Arc<governor::DefaultDirectRateLimiter>>,
+    status: Mutex<Option<ProgressBar>>,
+}
+
+/// A download slot, given back to the `Throttle` when dropped.
+pub struct Slot<'a> {
+    throttle: &'a Throttle,
+}
+
+/// Parse `--rate-limit`, which has to be a positive number of requests.
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(format!("`{s}` isn't a positive number")),
+    }
+}
+
+impl Throttle {
+    pub fn new(max_jobs: usize, max_rate: f64) -> Self {
+        let max_jobs = max_jobs.max(1);
+        let max_rate = max_rate.clamp(MIN_RATE, MAX_RATE);
+
+        Self {
+            max_jobs,
+            max_rate,
+            state: Mutex::new(State {
+                jobs: max_jobs,
+                active: 0,
+                rate: max_rate,
+                page: Backoff::default(),
+                download: Backoff::default(),
+            }),
+            slot_freed: Condvar::new(),
+            limiter: RwLock::new(Arc::new(Self::limiter(max_rate))),
+            status: Mutex::new(None),
+        }
+    }
+
+    fn burst(rate: f64) -> NonZeroU32 {
+        NonZeroU32::new(rate.floor() as u32).unwrap_or(NonZeroU32::MIN)
+    }
+
+    fn limiter(rate: f64) -> governor::DefaultDirectRateLimiter {
+        let period = Duration::from_secs_f64(1.0 / rate);
+        let quota = Quota::with_period(period)
+            .unwrap()
+            .allow_burst(Self::burst(rate));
+
+        RateLimiter::direct(quota)
+    }
+
+    /// Swap in a limiter for a new request rate. It starts with its burst
+    /// already used up, otherwise every change of rate would let a burst of
+    /// requests straight through.
+    fn set_rate(&self, rate: f64) {
+        let limiter = Self::limiter(rate);
+        let _ = limiter.check_n(Self::burst(rate));
+        *self.limiter.write().unwrap() = Arc::new(limiter);
+    }
+
+    /// Show the current effective limits in the given progress bar.
+    pub fn set_status_bar(&self, pb: ProgressBar) {
+        let state = self.state.lock().unwrap();
+        pb.set_message(self.describe(&state));
+        *self.status.lock().unwrap() = Some(pb);
+    }
+
+    fn describe(&self, state: &State) -> String {
+        format!(
+            "Limits: {}/{} parallel downloads, {:.2}/{:.2} requests per second",
+            state.jobs, self.max_jobs, state.rate, self.max_rate
+        )
+    }
+
+    fn update_status(&self, state: &State) {
+        if let Some(pb) = self.status.lock().unwrap().as_ref() {
+            pb.set_message(self.describe(state));
+        }
+    }
+
+    /// Run `f` with the progress bars hidden, so log output isn't mangled.
+    fn suspend<F: FnOnce()>(&self, f: F) {
+        match self.status.lock().unwrap().as_ref() {
+            Some(pb) => pb.suspend(f),
+            None => f(),
+        }
+    }
+
+    /// Block until a request can be sent under the current request rate.
+    /// Downloads aren't rate limited, only their concurrency is.
+    pub fn until_ready(&self, class: RequestClass) {
+        if class == RequestClass::Page {
+            // Clone the limiter out so its lock isn't held while waiting.
+            let limiter = self.limiter.read().unwrap().clone();
+            limiter.until_ready().block_on();
+        }
+    }
+
+    /// Block until a download slot is available under the current concurrency.
+    pub fn acquire(&self) -> Slot<'_> {
+        let mut state = self.state.lock().unwrap();
+        while state.active >= state.jobs {
+            state = self.slot_freed.wait(state).unwrap();
+        }
+        state.active += 1;
+
+        Slot { throttle: self }
+    }
+
+    /// Record that Bandcamp is throttling a class of requests, and back off.
+    pub fn on_throttled(&self, class: RequestClass) {
+        let mut state = self.state.lock().unwrap();
+        let backoff = state.backoff(class);
+        backoff.successes = 0;
+
+        if backoff
+            .last
+            .is_some_and(|at| at.elapsed() < BACKOFF_COOLDOWN)
+        {
+            return;
+        }
+
+        backoff.last = Some(Instant::now());
+        match class {
+            RequestClass::Page => {
+                state.rate = (state.rate / 2.0).max(MIN_RATE);
+                self.set_rate(state.rate);
+            }
+            RequestClass::Download => state.jobs = (state.jobs / 2).max(1),
+        }
+
+        self.suspend(|| {
+            warn!(
+                "Bandcamp is throttling requests, backing off to {} parallel downloads and {:.2} requests per second",
+                state.jobs, state.rate
+            )
+        });
+        self.update_status(&state);
+    }
+
+    /// Record a successful request, slowly raising the limit for its class
+    /// again if we've previously backed off.
+    pub fn on_success(&self, class: RequestClass) {
+        let mut state = self.state.lock().unwrap();
+        let recovered = match class {
+            RequestClass::Page => state.rate >= self.max_rate,
+            RequestClass::Download => state.jobs == self.max_jobs,
+        };
+        if recovered {
+            return;
+        }
+
+        let backoff = state.backoff(class);
+        backoff.successes += 1;
+        if backoff.successes < SUCCESSES_BEFORE_INCREASE {
+            return;
+        }
+
+        backoff.successes = 0;
+        match class {
+            RequestClass::Page => {
+                state.rate = (state.rate + RATE_STEP).min(self.max_rate);
+                self.set_rate(state.rate);
+            }
+            RequestClass::Download => state.jobs = (state.jobs + 1).min(self.max_jobs),
+        }
+
+        self.suspend(|| {
+            debug!(
+                "Raising limits to {} parallel downloads and {:.2} requests per second",
+                state.jobs, state.rate
+            )
+        });
+        self.update_status(&state);
+        self.slot_freed.notify_all();
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut state = self.throttle.state.lock().unwrap();
+        state.active -= 1;
+        self.throttle.slot_freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(throttle: &Throttle) -> (usize, f64) {
+        let state = throttle.state.lock().unwrap();
+        (state.jobs, state.rate)
+    }
+
+    #[test]
+    fn parses_positive_rates() {
+        assert_eq!(parse_rate("2.5"), Ok(2.5));
+        for rate in ["0", "-1", "NaN", "inf", "fast"] {
+            assert!(parse_rate(rate).is_err(), "{rate}");
+        }
+    }
+
+    #[test]
+    fn backs_off_once_per_burst() {
+        let throttle = Throttle::new(4, 4.0);
+        throttle.on_throttled(RequestClass::Page);
+        throttle.on_throttled(RequestClass::Page);
+        assert_eq!(limits(&throttle), (4, 2.0));
+
+        throttle.on_throttled(RequestClass::Download);
+        throttle.on_throttled(RequestClass::Download);
+        assert_eq!(limits(&throttle), (2, 2.0));
+    }
+
+    #[test]
+    fn never_goes_below_the_minimums() {
+        let throttle = Throttle::new(1, MIN_RATE);
+        throttle.on_throttled(RequestClass::Page);
+        throttle.on_throttled(RequestClass::Download);
+        assert_eq!(limits(&throttle), (1, MIN_RATE));
+    }
+
+    #[test]
+    fn recovers_from_successes_of_the_same_class() {
+        let throttle = Throttle::new(4, 4.0);
+        throttle.on_throttled(RequestClass::Page);
+        throttle.on_throttled(RequestClass::Download);
+
+        for _ in 0..SUCCESSES_BEFORE_INCREASE {
+            throttle.on_success(RequestClass::Download);
+        }
+        assert_eq!(limits(&throttle), (3, 2.0));
+
+        for _ in 0..SUCCESSES_BEFORE_INCREASE * 4 {
+            throttle.on_success(RequestClass::Page);
+        }
+        assert_eq!(limits(&throttle), (3, 4.0));
+    }
+
+    #[test]
+    fn throttling_resets_the_success_streak() {
+        let throttle = Throttle::new(4, 4.0);
+        throttle.on_throttled(RequestClass::Page);
+        for _ in 0..SUCCESSES_BEFORE_INCREASE - 1 {
+            throttle.on_success(RequestClass::Page);
+        }
+        // Still within the cooldown, so the limit stays where it is.
+        throttle.on_throttled(RequestClass::Page);
+        throttle.on_success(RequestClass::Page);
+        assert_eq!(limits(&throttle), (4, 2.0));
+    }
+}
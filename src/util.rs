@@ -1,45 +1,97 @@
-use phf::phf_map;
+use crate::sanitise::Sanitiser;
+use governor::{Quota, RateLimiter};
+use pollster::FutureExt as _;
 use std::{
     collections::VecDeque,
     io::{self, Read, Write},
+    num::NonZeroU32,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-// From https://github.com/Ezwen/bandcamp-collection-downloader/blob/master/src/main/kotlin/bandcampcollectiondownloader/core/Constants.kt#L7
-static REPLACEMENT_CHARS: phf::Map<&str, &str> = phf_map! {
-    ":" => "꞉",
-    "/" => "／",
-    "\\" => "⧹",
-    "\"" => "＂",
-    "*" => "⋆",
-    "<" => "＜",
-    ">" => "＞",
-    "?" => "？",
-    "|" => "∣"
-};
+/// Extract the filename from a raw `Content-Disposition` header value,
+/// following RFC 6266. An RFC 5987 `filename*` parameter is preferred over a
+/// plain `filename` when both are present.
+///
+/// Bandcamp puts raw UTF-8 into the plain `filename` parameter, so the value is
+/// taken as bytes rather than the ASCII-only `HeaderValue::to_str`. The
+/// filename is made safe with `sanitiser`.
+pub fn parse_content_disposition(value: &[u8], sanitiser: &Sanitiser) -> Option<String> {
+    let value = String::from_utf8_lossy(value);
+    let mut filename = None;
+    let mut filename_ext = None;
 
-// NTFS doesn't like these and pretty much shits itself if you try to do
-// anything to files/folders containing em.
-static UNSAFE_NTFS_ENDINGS: &[char] = &['.', ' '];
+    // Skip past the disposition type (`attachment`, `inline`, ...).
+    let (_, mut rest) = value.split_once(';')?;
 
-pub fn make_string_fs_safe(s: &str) -> String {
-    let mut str = s.to_string();
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ';']);
+        if rest.is_empty() {
+            break;
+        }
 
-    for (from, to) in REPLACEMENT_CHARS.entries() {
-        str = str.replace(from, to);
-    }
+        let Some((name, after)) = rest.split_once('=') else {
+            break;
+        };
+        let name = name.trim().to_ascii_lowercase();
+        let after = after.trim_start();
+
+        let (param, remaining) = if let Some(quoted) = after.strip_prefix('"') {
+            // quoted-string, which may contain escaped characters and semicolons.
+            let mut param = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some((_, escaped)) = chars.next() {
+                            param.push(escaped);
+                        }
+                    }
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    c => param.push(c),
+                }
+            }
+
+            (param, &quoted[end..])
+        } else {
+            let end = after.find(';').unwrap_or(after.len());
+            (after[..end].trim_end().to_string(), &after[end..])
+        };
+
+        match name.as_str() {
+            "filename" => filename = Some(param),
+            "filename*" => filename_ext = decode_ext_value(&param),
+            _ => (),
+        }
 
-    if UNSAFE_NTFS_ENDINGS.contains(&str.chars().last().unwrap()) {
-        str.push('_');
+        rest = remaining;
     }
 
-    str
+    filename_ext
+        .or(filename)
+        .map(|name| sanitiser.sanitise(name.trim()))
+        .filter(|name| !name.is_empty())
 }
 
-pub fn slice_string(s: &str, amt: usize) -> &str {
-    match s.char_indices().nth(amt) {
-        Some((pos, _)) => &s[pos..],
-        None => "",
+/// Decode an RFC 5987 `ext-value` (`charset'language'percent-encoded`).
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let encoded = parts.next()?;
+    let bytes = percent_encoding::percent_decode_str(encoded).collect::<Vec<u8>>();
+
+    if charset.eq_ignore_ascii_case("utf-8") {
+        String::from_utf8(bytes).ok()
+    } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+        Some(bytes.into_iter().map(char::from).collect())
+    } else {
+        None
     }
 }
 
@@ -95,19 +147,54 @@ impl<T> WorkQueue<T> {
 
 const DEFAULT_BUF_SIZE: usize = 8192;
 
-// `std::io::copy` slightly modified to update a progress bar as it copies
+/// Limits used to detect a download that has stopped making progress.
+#[derive(Clone, Copy, Debug)]
+pub struct TransferLimits {
+    /// How long a transfer can stay below `min_speed` before being aborted.
+    pub stall_timeout: Duration,
+    /// Minimum average throughput, in bytes per second.
+    pub min_speed: u64,
+}
+
+impl Default for TransferLimits {
+    fn default() -> Self {
+        Self {
+            stall_timeout: Duration::from_secs(30),
+            min_speed: 1024,
+        }
+    }
+}
+
+/// Create a limiter for a download speed in KiB/s, where each cell is one KiB.
+pub fn bandwidth_limiter(kib_per_sec: NonZeroU32) -> governor::DefaultDirectRateLimiter {
+    // Burst has to fit at least a full read buffer, or `until_n_ready` fails.
+    let burst = kib_per_sec.max(NonZeroU32::new((DEFAULT_BUF_SIZE / 1024) as u32).unwrap());
+    RateLimiter::direct(Quota::per_second(kib_per_sec).allow_burst(burst))
+}
+
+// `std::io::copy` slightly modified to update a progress bar as it copies, to
+// abort with `ErrorKind::TimedOut` if the transfer stalls, and to respect an
+// optional bandwidth limit.
 // https://doc.rust-lang.org/1.8.0/src/std/up/src/libstd/io/util.rs.html#46-61
-pub fn copy_with_progress<R: ?Sized, W: ?Sized>(
+pub fn copy_with_progress<R, W>(
     reader: &mut R,
     writer: &mut W,
     pb: &indicatif::ProgressBar,
+    limits: &TransferLimits,
+    bandwidth: Option<&governor::DefaultDirectRateLimiter>,
 ) -> io::Result<u64>
 where
-    R: Read,
-    W: Write,
+    R: Read + ?Sized,
+    W: Write + ?Sized,
 {
     let mut buf = [0; DEFAULT_BUF_SIZE];
     let mut written = 0;
+    let mut window_start = Instant::now();
+    let mut window_bytes = 0;
+    // The bandwidth limit is counted in whole KiB, so the rest of each read is
+    // carried over to the next instead of being rounded up.
+    let mut uncharged = 0;
+
     loop {
         let len = match reader.read(&mut buf) {
             Ok(0) => return Ok(written),
@@ -118,5 +205,179 @@ where
         writer.write_all(&buf[..len])?;
         written += len as u64;
         pb.set_position(written);
+
+        if let Some(bandwidth) = bandwidth {
+            uncharged += len;
+            if let Some(kib) = NonZeroU32::new((uncharged / 1024) as u32) {
+                uncharged %= 1024;
+                // Can't fail, as the burst is always at least a full buffer.
+                let _ = bandwidth.until_n_ready(kib).block_on();
+            }
+        }
+
+        window_bytes += len as u64;
+        let elapsed = window_start.elapsed();
+
+        if elapsed >= limits.stall_timeout {
+            let speed = window_bytes / elapsed.as_secs().max(1);
+
+            if speed < limits.min_speed {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("transfer stalled at {speed} B/s for {}s", elapsed.as_secs()),
+                ));
+            }
+
+            window_start = Instant::now();
+            window_bytes = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitise::FsProfile;
+
+    fn parse(value: &str) -> Option<String> {
+        parse_content_disposition(
+            value.as_bytes(),
+            &Sanitiser::new(FsProfile::Posix, &[], false),
+        )
+    }
+
+    #[test]
+    fn parses_plain_filenames() {
+        assert_eq!(
+            parse("attachment; filename=\"Artist - Album.zip\"").as_deref(),
+            Some("Artist - Album.zip")
+        );
+        assert_eq!(
+            parse("attachment; filename=Album.zip").as_deref(),
+            Some("Album.zip")
+        );
+        assert_eq!(
+            parse("ATTACHMENT;FILENAME = Album.zip ; size=10").as_deref(),
+            Some("Album.zip")
+        );
+    }
+
+    #[test]
+    fn parses_quoted_strings() {
+        assert_eq!(
+            parse(r#"attachment; filename="a \"b\"; c\\d.zip"; size=10"#).as_deref(),
+            Some(r#"a "b"; c\d.zip"#)
+        );
+        // An unterminated string runs to the end of the header.
+        assert_eq!(
+            parse(r#"attachment; filename="Album.zip"#).as_deref(),
+            Some("Album.zip")
+        );
+    }
+
+    #[test]
+    fn prefers_extended_filenames() {
+        let header = "attachment; filename=\"fallback.zip\"; filename*=UTF-8''%E3%83%86%E3%82%B9%E3%83%88.zip";
+        assert_eq!(parse(header).as_deref(), Some("テスト.zip"));
+
+        // Even when it comes first.
+        let header = "attachment; filename*=utf-8'en'caf%C3%A9.zip; filename=\"cafe.zip\"";
+        assert_eq!(parse(header).as_deref(), Some("café.zip"));
+
+        let header = "attachment; filename*=iso-8859-1''caf%E9.zip";
+        assert_eq!(parse(header).as_deref(), Some("café.zip"));
+    }
+
+    #[test]
+    fn falls_back_from_broken_extended_filenames() {
+        for header in [
+            "attachment; filename=\"fallback.zip\"; filename*=UTF-8''%FF.zip",
+            "attachment; filename=\"fallback.zip\"; filename*=koi8-r''abc.zip",
+            "attachment; filename=\"fallback.zip\"; filename*=nonsense",
+        ] {
+            assert_eq!(parse(header).as_deref(), Some("fallback.zip"), "{header}");
+        }
+    }
+
+    #[test]
+    fn keeps_raw_utf8() {
+        let header = "attachment; filename=\"Ålbum – ライブ.zip\"".as_bytes();
+        let sanitiser = Sanitiser::new(FsProfile::Posix, &[], false);
+        assert_eq!(
+            parse_content_disposition(header, &sanitiser).as_deref(),
+            Some("Ålbum – ライブ.zip")
+        );
+    }
+
+    #[test]
+    fn rejects_missing_filenames() {
+        for header in [
+            "",
+            "attachment",
+            "attachment; size=10",
+            "attachment; filename=\"\"",
+            "attachment; filename=\"   \"",
+            "attachment; filename",
+        ] {
+            assert_eq!(parse(header), None, "{header}");
+        }
+    }
+
+    #[test]
+    fn sanitises_with_the_given_profile() {
+        let header = "attachment; filename=\"../What? A: B.zip\"".as_bytes();
+
+        let posix = Sanitiser::new(FsProfile::Posix, &[], false);
+        let name = parse_content_disposition(header, &posix).unwrap();
+        assert_eq!(name, "..／What? A: B.zip");
+
+        let ntfs = Sanitiser::new(FsProfile::Ntfs, &[], false);
+        let name = parse_content_disposition(header, &ntfs).unwrap();
+        assert_eq!(name, "..／What？ A꞉ B.zip");
+
+        let ascii = Sanitiser::new(FsProfile::Ntfs, &[], true);
+        let name = parse_content_disposition(header, &ascii).unwrap();
+        assert_eq!(name, "..-What_ A- B.zip");
+    }
+
+    /// Reads a little at a time, like a slow connection.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.0.len().min(buf.len()).min(100);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn only_charges_whole_kib_of_bandwidth() {
+        let kib = |n| NonZeroU32::new(n).unwrap();
+        // Hardly refills, so what's left shows how much was charged.
+        let bandwidth = governor::RateLimiter::direct(
+            governor::Quota::per_hour(kib(100)).allow_burst(kib(100)),
+        );
+        let data = vec![0; 10 * 1024 + 512];
+        let limits = TransferLimits {
+            stall_timeout: std::time::Duration::from_secs(30),
+            min_speed: 0,
+        };
+
+        let mut out = vec![];
+        let written = copy_with_progress(
+            &mut Trickle(&data),
+            &mut out,
+            &indicatif::ProgressBar::hidden(),
+            &limits,
+            Some(&bandwidth),
+        )
+        .unwrap();
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(out, data);
+
+        assert!(bandwidth.check_n(kib(90)).unwrap().is_ok());
+        assert!(bandwidth.check().is_err());
     }
 }